@@ -0,0 +1,363 @@
+use std::io::{File, IoResult, IoError, InvalidInput, BufferedReader, BufferedWriter};
+use std::io::fs;
+use std::default::Default;
+
+use encoding::{Encoding, Polygon, Pixel, Point};
+use config::CompressorConfig;
+use rng::GpeRng;
+use compress::ERROR_GRID;
+
+// Layout of a checkpoint file (all integers big-endian):
+//
+//   magic "GPEC", version u8, input hash u64, iteration u64, RNG state 4 x u32,
+//   plateau restarts u32, seconds spent so far f64,
+//   score history length u32 and scores as f32,
+//   config length u32 and config as `key = value` lines,
+//   error map length u32 and cells as f32,
+//   population count u32, per individual: error f64 and encoding.
+//
+// Encodings are stored the way they are in memory, unlike .gpe files:
+// width u32, height u32, alpha u8, polygon count u32, pixel count u32,
+//   per polygon: vertex count u32, color RGBA, blur f32, vertices as (x f32, y f32),
+//   per pixel: x f32, y f32, color RGBA.
+//
+// Together with the input image, this is everything a run depends on, so a
+// resumed run carries on exactly as the original would have.
+
+static MAGIC: &'static [u8] = b"GPEC";
+static VERSION: u8 = 1;
+// far more than the `key = value` lines of any config take up
+static MAX_CONFIG_SIZE: uint = 64 * 1024;
+
+pub struct Checkpoint {
+    pub config: CompressorConfig,
+    // FNV hash of the input's pixels, to catch resuming with the wrong image
+    pub input_hash: u64,
+    pub iteration: uint,
+    pub rng: GpeRng,
+    pub error: Vec<f32>,
+    pub population: Vec<(Encoding, f64)>,
+    // best scores of the latest generations, oldest first
+    pub scores: Vec<f32>,
+    pub restarts: uint,
+    // seconds the run had spent when the checkpoint was made
+    pub elapsed: f64,
+}
+
+fn invalid(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError { kind: InvalidInput, desc: desc, detail: detail }
+}
+
+pub fn write<W: Writer>(checkpoint: &Checkpoint, w: &mut W) -> IoResult<()> {
+    try!(w.write(MAGIC));
+    try!(w.write_u8(VERSION));
+    try!(w.write_be_u64(checkpoint.input_hash));
+    try!(w.write_be_u64(checkpoint.iteration as u64));
+    let (x, y, z, s) = checkpoint.rng.state();
+    for &word in [x, y, z, s].iter() {
+        try!(w.write_be_u32(word));
+    }
+
+    try!(w.write_be_u32(checkpoint.restarts as u32));
+    try!(w.write_be_f64(checkpoint.elapsed));
+    try!(w.write_be_u32(checkpoint.scores.len() as u32));
+    for &score in checkpoint.scores.iter() {
+        try!(w.write_be_f32(score));
+    }
+
+    let config = checkpoint.config.assignments();
+    try!(w.write_be_u32(config.len() as u32));
+    try!(w.write_str(config.as_slice()));
+
+    try!(w.write_be_u32(checkpoint.error.len() as u32));
+    for &cell in checkpoint.error.iter() {
+        try!(w.write_be_f32(cell));
+    }
+
+    try!(w.write_be_u32(checkpoint.population.len() as u32));
+    for &(ref encoding, error) in checkpoint.population.iter() {
+        try!(w.write_be_f64(error));
+        try!(write_encoding(encoding, w));
+    }
+
+    Ok(())
+}
+
+fn write_encoding<W: Writer>(img: &Encoding, w: &mut W) -> IoResult<()> {
+    let (width, height) = img.dimensions;
+    try!(w.write_be_u32(width));
+    try!(w.write_be_u32(height));
+    try!(w.write_u8(img.alpha as u8));
+    try!(w.write_be_u32(img.polygons.len() as u32));
+    try!(w.write_be_u32(img.pixels.len() as u32));
+
+    for polygon in img.polygons.iter() {
+        let (r, g, b, a) = polygon.color;
+        try!(w.write_be_u32(polygon.vertices.len() as u32));
+        try!(w.write(&[r, g, b, a]));
+        try!(w.write_be_f32(polygon.blur));
+        for vertex in polygon.vertices.iter() {
+            try!(w.write_be_f32(vertex.x));
+            try!(w.write_be_f32(vertex.y));
+        }
+    }
+
+    for pixel in img.pixels.iter() {
+        let (r, g, b, a) = pixel.color;
+        try!(w.write_be_f32(pixel.pos.x));
+        try!(w.write_be_f32(pixel.pos.y));
+        try!(w.write(&[r, g, b, a]));
+    }
+
+    Ok(())
+}
+
+pub fn read<R: Reader>(r: &mut R) -> IoResult<Checkpoint> {
+    let magic = try!(r.read_exact(MAGIC.len()));
+    if magic.as_slice() != MAGIC {
+        return Err(invalid("not a GPE checkpoint", None));
+    }
+    let version = try!(r.read_u8());
+    if version != VERSION {
+        return Err(invalid("unsupported GPE checkpoint version", None));
+    }
+
+    let input_hash = try!(r.read_be_u64());
+    let iteration = try!(r.read_be_u64()) as uint;
+    let state = (try!(r.read_be_u32()), try!(r.read_be_u32()),
+                 try!(r.read_be_u32()), try!(r.read_be_u32()));
+
+    let restarts = try!(r.read_be_u32()) as uint;
+    let elapsed = try!(r.read_be_f64());
+    // counts are read from the file, so nothing is allocated up front from them
+    let count = try!(r.read_be_u32()) as uint;
+    let mut scores = Vec::new();
+    for _ in range(0, count) {
+        scores.push(try!(r.read_be_f32()));
+    }
+
+    let length = try!(r.read_be_u32()) as uint;
+    if length > MAX_CONFIG_SIZE {
+        return Err(invalid("GPE checkpoint config is too long", None));
+    }
+    let text = match String::from_utf8(try!(r.read_exact(length))) {
+        Ok(text) => text,
+        Err(_) => return Err(invalid("GPE checkpoint config is not UTF-8", None)),
+    };
+    let mut config: CompressorConfig = Default::default();
+    for line in text.as_slice().lines() {
+        match config.set_assignment(line) {
+            Ok(()) => {},
+            Err(err) => return Err(invalid("invalid GPE checkpoint config", Some(err))),
+        }
+    }
+
+    let cells = try!(r.read_be_u32()) as uint;
+    if cells != 0 && cells != (ERROR_GRID * ERROR_GRID) as uint {
+        return Err(invalid("GPE checkpoint error map has the wrong size", None));
+    }
+    let mut error = Vec::new();
+    for _ in range(0, cells) {
+        error.push(try!(r.read_be_f32()));
+    }
+
+    let count = try!(r.read_be_u32()) as uint;
+    let mut population = Vec::new();
+    for _ in range(0, count) {
+        let error = try!(r.read_be_f64());
+        population.push((try!(read_encoding(r)), error));
+    }
+
+    Ok(Checkpoint { config: config,
+                    input_hash: input_hash,
+                    iteration: iteration,
+                    rng: GpeRng::from_state(state),
+                    error: error,
+                    population: population,
+                    scores: scores,
+                    restarts: restarts,
+                    elapsed: elapsed })
+}
+
+fn read_encoding<R: Reader>(r: &mut R) -> IoResult<Encoding> {
+    let width = try!(r.read_be_u32());
+    let height = try!(r.read_be_u32());
+    let alpha = try!(r.read_u8()) != 0;
+    let num_polygons = try!(r.read_be_u32()) as uint;
+    let num_pixels = try!(r.read_be_u32()) as uint;
+
+    let mut polygons = Vec::new();
+    for _ in range(0, num_polygons) {
+        let num_vertices = try!(r.read_be_u32()) as uint;
+        if num_vertices < 3 {
+            return Err(invalid("GPE checkpoint polygon has fewer than 3 vertices", None));
+        }
+
+        let color = try!(r.read_exact(4));
+        let blur = try!(r.read_be_f32());
+        let mut vertices = Vec::new();
+        for _ in range(0, num_vertices) {
+            let x = try!(r.read_be_f32());
+            let y = try!(r.read_be_f32());
+            vertices.push(Point { x: x, y: y });
+        }
+
+        let mut polygon = Polygon::new(vertices, (color[0], color[1], color[2], color[3]));
+        polygon.blur = blur;
+        polygons.push(polygon);
+    }
+
+    let mut pixels = Vec::new();
+    for _ in range(0, num_pixels) {
+        let x = try!(r.read_be_f32());
+        let y = try!(r.read_be_f32());
+        let color = try!(r.read_exact(4));
+        pixels.push(Pixel { pos: Point { x: x, y: y },
+                            color: (color[0], color[1], color[2], color[3]) });
+    }
+
+    Ok(Encoding { dimensions: (width, height),
+                  alpha: alpha,
+                  polygons: polygons,
+                  pixels: pixels })
+}
+
+// Writes to a temporary file first and moves it into place, so a crash while
+// saving leaves the previous checkpoint intact.
+pub fn save(checkpoint: &Checkpoint, path: &Path) -> IoResult<()> {
+    let tmp = path.with_extension("tmp");
+    {
+        let mut w = BufferedWriter::new(try!(File::create(&tmp)));
+        try!(write(checkpoint, &mut w));
+        try!(w.flush());
+    }
+
+    fs::rename(&tmp, path)
+}
+
+pub fn open(path: &Path) -> IoResult<Checkpoint> {
+    let mut r = BufferedReader::new(try!(File::open(path)));
+    read(&mut r)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::io::{MemWriter, BufReader};
+    use std::rand::Rng;
+
+    use compress::ERROR_GRID;
+    use config::CompressorConfig;
+    use encoding::{Encoding, Polygon, Pixel, Point};
+    use rng::GpeRng;
+    use super::{Checkpoint, read, write, MAX_CONFIG_SIZE};
+
+    // Unlike .gpe files, checkpoints have to keep vertices exactly as they are
+    // in memory, so these are nowhere near whole pixels.
+    fn sample() -> Checkpoint {
+        let mut config: CompressorConfig = Default::default();
+        config.seed = Some(7);
+        config.checkpoint_path = Some("run.ckpt".to_string());
+
+        let mut rng = GpeRng::new(7);
+        for _ in range(0u, 10) {
+            rng.next_u32();
+        }
+
+        let vertices = vec![Point { x: 0.3125, y: 17.770001 }, Point { x: 33.001, y: 0.0025 },
+                            Point { x: 12.5, y: 29.9 }, Point { x: 20.25, y: 11.1 }];
+        let mut polygon = Polygon::new(vertices, (10, 20, 30, 40));
+        polygon.blur = 0.37;
+        let encoding = Encoding { dimensions: (40, 30),
+                                  alpha: true,
+                                  polygons: vec![polygon],
+                                  pixels: vec![Pixel { pos: Point { x: 5.0, y: 6.0 },
+                                                       color: (9, 8, 7, 6) }] };
+
+        Checkpoint { config: config,
+                     input_hash: 0x0123456789abcdef,
+                     iteration: 300,
+                     rng: rng,
+                     error: Vec::from_fn((ERROR_GRID * ERROR_GRID) as uint, |i| i as f32 * 0.3),
+                     population: vec![(encoding, 12.25)],
+                     scores: vec![0.5, 0.625, 0.75],
+                     restarts: 2,
+                     elapsed: 42.5 }
+    }
+
+    fn written(checkpoint: &Checkpoint) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        write(checkpoint, &mut w).unwrap();
+        w.get_ref().to_vec()
+    }
+
+    // Where the config length sits: after the magic, version, hash, iteration,
+    // RNG state, restarts, elapsed time and score history.
+    fn config_offset(checkpoint: &Checkpoint) -> uint {
+        4 + 1 + 8 + 8 + 4 * 4 + 4 + 8 + 4 + 4 * checkpoint.scores.len()
+    }
+
+    fn set_u32(bytes: &mut Vec<u8>, at: uint, value: u32) {
+        for i in range(0u, 4) {
+            bytes[at + i] = (value >> (24 - 8 * i)) as u8;
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let checkpoint = sample();
+        let bytes = written(&checkpoint);
+        let mut decoded = read(&mut BufReader::new(bytes.as_slice())).unwrap();
+
+        assert_eq!(decoded.config.assignments(), checkpoint.config.assignments());
+        assert_eq!(decoded.input_hash, checkpoint.input_hash);
+        assert_eq!(decoded.iteration, checkpoint.iteration);
+        assert_eq!(decoded.error, checkpoint.error);
+        assert_eq!(decoded.scores, checkpoint.scores);
+        assert_eq!(decoded.restarts, checkpoint.restarts);
+        assert_eq!(decoded.elapsed, checkpoint.elapsed);
+
+        assert_eq!(decoded.population.len(), 1);
+        {
+            let (ref encoding, error) = decoded.population[0];
+            let (ref original, original_error) = checkpoint.population[0];
+            assert_eq!(error, original_error);
+            assert_eq!(encoding.dimensions, original.dimensions);
+            assert_eq!(encoding.alpha, original.alpha);
+
+            let (polygon, expected) = (&encoding.polygons[0], &original.polygons[0]);
+            assert_eq!(polygon.color, expected.color);
+            assert_eq!(polygon.blur, expected.blur);
+            assert_eq!(polygon.vertices.len(), expected.vertices.len());
+            for (v, e) in polygon.vertices.iter().zip(expected.vertices.iter()) {
+                assert_eq!((v.x, v.y), (e.x, e.y));
+            }
+
+            let (pixel, expected) = (&encoding.pixels[0], &original.pixels[0]);
+            assert_eq!((pixel.pos.x, pixel.pos.y, pixel.color),
+                       (expected.pos.x, expected.pos.y, expected.color));
+        }
+
+        // the restored generator carries on with the same numbers
+        assert_eq!(decoded.rng.state(), checkpoint.rng.state());
+        let mut rng = checkpoint.rng.clone();
+        assert_eq!(decoded.rng.next_u32(), rng.next_u32());
+    }
+
+    #[test]
+    fn rejects_oversized_config() {
+        let checkpoint = sample();
+        let mut bytes = written(&checkpoint);
+        set_u32(&mut bytes, config_offset(&checkpoint), (MAX_CONFIG_SIZE + 1) as u32);
+        assert!(read(&mut BufReader::new(bytes.as_slice())).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_error_map_size() {
+        let checkpoint = sample();
+        let mut bytes = written(&checkpoint);
+        let at = config_offset(&checkpoint) + 4 + checkpoint.config.assignments().len();
+        set_u32(&mut bytes, at, 7);
+        assert!(read(&mut BufReader::new(bytes.as_slice())).is_err());
+    }
+}
@@ -1,269 +1,647 @@
 extern crate image;
 extern crate time;
 
-use std::sync::{Arc, TaskPool};
-use std::rand::random;
+use std::sync::Arc;
+use std::rand::{Rng, random};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, RingBuf};
+use std::hash::Hasher;
 use std::io::File;
 use std::num::Float;
 use std::num::FloatMath;
-use std::cmp::max;
-
-use image::{GenericImage, imageops, ImageBuf};
-use encoding::{Encoding, Polygon, Pixel, Point, fmin, fmax};
-use render::{render, Image};
-use constants::*;
+use std::fmt;
+
+use image::{GenericImage, ImageBuf};
+use encoding::{Encoding, Polygon, Pixel, Point, Color, fmin, fmax};
+use render::{render, footprint, premultiply, Image, Rect};
+use config::{CompressorConfig, should_mutate};
+use format;
+use crossover;
+use selection::Selection;
+use fitness::Fitness;
+use rng::GpeRng;
+use fnvhasher::FnvHasher;
+use checkpoint;
+use checkpoint::Checkpoint;
+use pool::{WorkerPool, Offspring, Clean, Region, Everything};
+
+// The error map splits the image into ERROR_GRID x ERROR_GRID cells.
+pub static ERROR_GRID: u32 = 8;
+// How many times each polygon of the best individual is mutated to seed the
+// rest of the population when a run restarts from a plateau.
+static PERTURB_ROUNDS: uint = 10;
 
 pub struct Compressor {
     pub dimensions: (u32, u32),
+    pub alpha: bool,
     pub base: Arc<Image>,
-    pub downsampled: Arc<Image>,
-    pub error: Vec<uint>,
+    pub input_hash: u64,
+    pub error: Vec<f32>,
+    pub cell_size: (u32, u32),
     pub edges: Vec<(Point, f32, f32)>,
+    pub config: CompressorConfig,
+    pub rng: RefCell<GpeRng>,
+    pub selection: Box<Selection + 'static>,
+    pub fitness: Arc<Box<Fitness + Send + Sync>>,
+    pool: WorkerPool,
+    // scores and renders of recently evaluated encodings, by FNV hash
+    cache: RefCell<HashMap<u64, (f64, Arc<Image>), FnvHasher>>,
+    // individuals scored per second in the latest generation
+    throughput: Cell<f64>,
 }
 
-pub fn compress(img: image::ImageBuf<image::Rgb<u8>>) -> (Encoding, Encoding) {
-    let dimensions = img.dimensions();
-    let downsampled = Arc::new(imageops::resize(&img, 50, 50, image::Nearest)
-                               .into_vec().into_iter().map(|p| p.channels()).collect());
-    let base = Arc::new(img.into_vec().into_iter().map(|p| p.channels()).collect());
-    let mut compressor = Compressor { dimensions: dimensions,
-                                      base: base,
-                                      downsampled: downsampled,
-                                      error: vec![],
-                                      edges: vec![], };
-    compressor.detect_edges();
-    let mut population = compressor.create_population();
-    let max_score = compressor.max_score();
-    let max_iters = ::iterations();
-
-    let mut iteration = 0u;
-    let mut cur_time = time::get_time();
-    let mut avg_time = 0.0;
-    loop {
-        let (new_population, min_fitness, index) = compressor.mutate(population);
-
-        iteration += 1;
-        let new_time = time::get_time();
-        let diff = (new_time.nsec - cur_time.nsec) / 1000000;
-
-        let iter_f = iteration as f32;
-        avg_time = avg_time * (iter_f - 1.0) / iter_f +
-            (if diff < 0 { avg_time } else { diff as f32 }) / iter_f;
-        cur_time = new_time;
-
-        population = new_population;
-        let current_score = 1.0 - (min_fitness as f32 / max_score as f32);
-
-        if iteration % 10 == 0 {
-            info!("Iteration {} (size {}, score {}, time {}ms)", iteration,
-                  population[0].polygons.len(), current_score, diff);
-        }
+// Why a run stopped. Whichever limit is hit first ends it.
+#[deriving(Clone, PartialEq)]
+pub enum StopReason {
+    ScoreReached,
+    IterationLimit,
+    TimeLimit,
+    Plateau,
+}
 
-        if iteration % 30 == 0 {
-            compressor.compute_error(&population[index]);
+impl fmt::Show for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ScoreReached => write!(f, "reached the score threshold"),
+            IterationLimit => write!(f, "reached the iteration limit"),
+            TimeLimit => write!(f, "ran out of time"),
+            Plateau => write!(f, "stopped improving"),
         }
+    }
+}
 
-        if current_score >= ::threshold() || (max_iters != 0 && iteration >= max_iters) {
-            info!("Average time: {}ms", avg_time);
-            return (compressor.fix_pixels(population[index].clone()), population[index].clone());
-        }
+// Any input is compressed as RGBA. Only images that actually contain
+// translucent pixels produce an encoding with an alpha channel.
+pub fn compress(img: image::DynamicImage, config: CompressorConfig) -> (Encoding, Encoding) {
+    let seed = match config.seed {
+        Some(seed) => seed,
+        None => random(),
+    };
+    info!("Using seed {}", seed);
+
+    let mut compressor = Compressor::new(img, config, GpeRng::new(seed));
+    let population = compressor.create_population().into_iter()
+        .map(|encoding| compressor.individual(encoding)).collect();
+    compressor.run(population, 0, vec![], 0, 0.0)
+}
+
+// Carries on from a checkpoint of a run, which has to be given the same image.
+// `config` replaces the settings the run was checkpointed with. If it scores
+// renders differently, the saved errors mean nothing to it, so the population
+// is scored again and the plateau history starts over.
+pub fn resume(img: image::DynamicImage, checkpoint: Checkpoint, config: CompressorConfig)
+              -> Result<(Encoding, Encoding), String> {
+    let Checkpoint { config: saved, input_hash, iteration, rng, error, population, scores, restarts,
+                     elapsed } = checkpoint;
+    let rescore = !config.scores_like(&saved);
+    let mut compressor = Compressor::new(img, config, rng);
+    if compressor.input_hash != input_hash {
+        return Err("the checkpoint was made from a different image".to_string());
+    }
+    if population.is_empty() {
+        return Err("the checkpoint has no population".to_string());
     }
+    if population.iter().any(|&(ref encoding, _)| encoding.dimensions != compressor.dimensions) {
+        return Err("the checkpoint's encodings don't match the image's dimensions".to_string());
+    }
+    info!("Resuming at iteration {}", iteration);
+
+    compressor.error = error;
+    let population = population.into_iter().map(|(encoding, error)| {
+        if rescore {
+            compressor.individual(encoding)
+        } else {
+            Individual { render: Arc::new(render(&encoding, compressor.config.antialias)),
+                         encoding: encoding,
+                         error: error }
+        }
+    }).collect();
+    let scores = if rescore {
+        info!("Scoring settings changed, rescored the population");
+        vec![]
+    } else {
+        scores
+    };
+    Ok(compressor.run(population, iteration, scores, restarts, elapsed))
 }
 
-#[inline(always)]
-fn diff(a: u8, b: u8) -> uint {
-    let diff = if a > b { (a - b) as uint } else { (b - a) as uint };
-    diff
+// A member of the population, along with its render so that its offspring
+// can be scored by re-rendering only what they changed.
+#[deriving(Clone)]
+pub struct Individual {
+    pub encoding: Encoding,
+    pub render: Arc<Image>,
+    pub error: f64,
 }
 
-fn fitness((w, h): (u32, u32), base: Arc<Image>, downsampled: Arc<Image>, individual: Arc<Option<Encoding>>) -> uint {
-    let mut score = 0;
-    let individual = individual.as_ref().unwrap();
-    let new_render = render(individual, false);
-
-    /*
-    let new_downsampled: Image = imageops::resize(
-        &ImageBuf::from_pixels(
-            new_render.iter().map(|&(r, g, b): &(u8, u8, u8)|
-                                  image::Rgb(r, g, b)).collect(),
-            w, h),
-        50, 50, image::Nearest).into_vec().into_iter().map(|p| p.channels()).collect();
-
-    // fitness on downsampling as well
-    for i in range(0u, 50 * 50) {
-        let (br, bg, bb) = downsampled[i];
-        let (nr, ng, nb) = new_downsampled[i];
-
-        score += 4 * (diff(br, nr) + diff(bg, ng) + diff(bb, nb));
+impl Compressor {
+    pub fn new(img: image::DynamicImage, config: CompressorConfig, rng: GpeRng) -> Compressor {
+        let img = img.to_rgba();
+        let dimensions = img.dimensions();
+        let (w, h) = dimensions;
+        let base: Image = img.into_vec().into_iter().map(|p| p.channels()).collect();
+        let alpha = base.iter().any(|&(_, _, _, a)| a < 255);
+        let input_hash = FnvHasher.hash(&base);
+        let base = Arc::new(base);
+        let metric = config.fitness.build(base.clone(), dimensions, alpha, &config);
+        let selection = config.selection.build(config.tournament_size);
+        let pool = WorkerPool::new(config.workers, metric.clone(), config.antialias);
+
+        let mut compressor = Compressor { dimensions: dimensions,
+                                          alpha: alpha,
+                                          base: base,
+                                          input_hash: input_hash,
+                                          error: vec![],
+                                          cell_size: ((w + ERROR_GRID - 1) / ERROR_GRID,
+                                                      (h + ERROR_GRID - 1) / ERROR_GRID),
+                                          edges: vec![],
+                                          config: config,
+                                          rng: RefCell::new(rng),
+                                          selection: selection,
+                                          fitness: metric,
+                                          pool: pool,
+                                          cache: RefCell::new(HashMap::with_hasher(FnvHasher)),
+                                          throughput: Cell::new(0.0) };
+        compressor.detect_edges();
+        compressor
     }
-     */
 
-    for i in range(0, w * h) {
-        let (br, bg, bb) = base[i as uint];
-        let (nr, ng, nb) = new_render[i as uint];
+    // `scores` are the best scores of the latest generations, oldest first, and
+    // `restarts` how many times the run has already restarted from a plateau.
+    // `elapsed` is the time in seconds already spent on the run before this call.
+    fn run(&mut self, mut population: Vec<Individual>, mut iteration: uint,
+           scores: Vec<f32>, mut restarts: uint, elapsed: f64) -> (Encoding, Encoding) {
+        let max_score = self.max_score();
+        // backdated so that a resumed run counts the time spent before its checkpoint
+        let start = time::precise_time_s() - elapsed;
+        let mut scores: RingBuf<f32> = scores.into_iter().collect();
+
+        let mut cur_time = time::get_time();
+        let mut avg_time = 0.0;
+        let mut timed = 0u;
+        loop {
+            let (new_population, min_fitness, index) = self.mutate(population);
+
+            iteration += 1;
+            timed += 1;
+            let new_time = time::get_time();
+            let diff = (new_time.nsec - cur_time.nsec) / 1000000;
+
+            let iter_f = timed as f32;
+            avg_time = avg_time * (iter_f - 1.0) / iter_f +
+                (if diff < 0 { avg_time } else { diff as f32 }) / iter_f;
+            cur_time = new_time;
+
+            population = new_population;
+            let current_score = if max_score > 0.0 { 1.0 - (min_fitness / max_score) as f32 } else { 1.0 };
+
+            if iteration % 10 == 0 {
+                info!("Iteration {} (size {}, score {}, time {}ms, {:.0} scored per second)", iteration,
+                      population[0].encoding.polygons.len(), current_score, diff, self.throughput.get());
+            }
+
+            if iteration % 30 == 0 {
+                self.compute_error(&population[index].encoding);
+            }
 
-        score += diff(br, nr) + diff(bg, ng) + diff(bb, nb);
+            // the run has plateaued once the best score improved by no more than
+            // plateau_epsilon over the last plateau_window generations
+            let window = self.config.plateau_window;
+            scores.push_back(current_score);
+            while scores.len() > window + 1 {
+                scores.pop_front();
+            }
+            let plateaued = window != 0 && scores.len() == window + 1 &&
+                *scores.back().unwrap() - *scores.front().unwrap() <= self.config.plateau_epsilon;
+
+            let stuck = plateaued && restarts >= self.config.plateau_restarts;
+            match self.should_stop(current_score, iteration, time::precise_time_s() - start, stuck) {
+                Some(reason) => {
+                    info!("Stopping after {} iterations: {}", iteration, reason);
+                    info!("Average time: {}ms", avg_time);
+                    let best = format::quantize(&population[index].encoding);
+                    return (self.fix_pixels(best.clone()), best);
+                }
+                None => {}
+            }
+
+            if plateaued {
+                restarts += 1;
+                info!("Score {} plateaued over {} iterations, perturbing the population (restart {} of {})",
+                      current_score, window, restarts, self.config.plateau_restarts);
+                population = self.perturb(population, index);
+                scores.clear();
+            }
+
+            let interval = self.config.checkpoint_interval;
+            if interval != 0 && iteration % interval == 0 {
+                match self.config.checkpoint_path {
+                    Some(ref path) => {
+                        let scores: Vec<f32> = scores.iter().map(|&score| score).collect();
+                        self.save_checkpoint(&Path::new(path.as_slice()), population.as_slice(),
+                                             iteration, scores, restarts,
+                                             time::precise_time_s() - start)
+                    }
+                    None => {}
+                }
+            }
+        }
     }
 
+    // `elapsed` is the time in seconds spent on the run, including before any resume.
+    fn should_stop(&self, score: f32, iteration: uint, elapsed: f64, plateaued: bool)
+                   -> Option<StopReason> {
+        let config = &self.config;
+        if score >= config.threshold {
+            Some(ScoreReached)
+        } else if config.iterations != 0 && iteration >= config.iterations {
+            Some(IterationLimit)
+        } else if config.time_limit > 0.0 && elapsed >= config.time_limit {
+            Some(TimeLimit)
+        } else if plateaued {
+            Some(Plateau)
+        } else {
+            None
+        }
+    }
 
-    score
-}
+    // Keeps the individual at `index` and replaces everyone else with heavily
+    // mutated copies of it, to shake a stagnant run out of its local optimum.
+    fn perturb(&self, population: Vec<Individual>, index: uint) -> Vec<Individual> {
+        let mut encodings = vec![];
+        {
+            let mut rng = self.rng.borrow_mut();
+            for _ in range(1, population.len()) {
+                let mut encoding = population[index].encoding.clone();
+                for polygon in encoding.polygons.iter_mut() {
+                    for _ in range(0, PERTURB_ROUNDS) {
+                        polygon.mutate(self, &mut *rng);
+                    }
+                }
+                if self.over_budget(&encoding) {
+                    encoding = population[index].encoding.clone();
+                }
+                encodings.push(encoding);
+            }
+        }
+
+        let mut perturbed = vec![population[index].clone()];
+        perturbed.extend(encodings.into_iter().map(|encoding| self.individual(encoding)));
+        perturbed
+    }
+
+    fn save_checkpoint(&self, path: &Path, population: &[Individual], iteration: uint,
+                       scores: Vec<f32>, restarts: uint, elapsed: f64) {
+        // a resumed run starts with an empty cache, so this one has to as well
+        // for the two to score (and so evolve) the same from here on
+        self.cache.borrow_mut().clear();
+
+        let checkpoint = Checkpoint {
+            config: self.config.clone(),
+            input_hash: self.input_hash,
+            iteration: iteration,
+            rng: self.rng.borrow().clone(),
+            error: self.error.clone(),
+            population: population.iter().map(|individual| {
+                (individual.encoding.clone(), individual.error)
+            }).collect(),
+            scores: scores,
+            restarts: restarts,
+            elapsed: elapsed,
+        };
+
+        match checkpoint::save(&checkpoint, path) {
+            Ok(()) => info!("Saved checkpoint to {}", path.display()),
+            // losing a checkpoint shouldn't end the run
+            Err(err) => error!("could not write checkpoint {}: {}", path.display(), err),
+        }
+    }
 
-impl Compressor {
     fn create_population(&self) -> Vec<Encoding> {
+        let mut rng = self.rng.borrow_mut();
         let mut population = vec![];
-        for _ in range(0, POPULATION_SIZE) {
-            let mut polygons = vec![];
-            for _ in range(0, INITIAL_POLYGONS) {
-                match Polygon::random(self) {
-                    Some(p) => { polygons.push(p); },
+        for _ in range(0, self.config.population_size) {
+            let mut encoding = Encoding { dimensions: self.dimensions,
+                                          alpha: self.alpha,
+                                          polygons: vec![],
+                                          pixels: vec![] };
+            for _ in range(0, self.config.initial_polygons) {
+                match Polygon::random(self, &mut *rng) {
+                    Some(p) => {
+                        encoding.polygons.push(p);
+                        if self.over_budget(&encoding) {
+                            encoding.polygons.pop();
+                        }
+                    },
                     None => {}
                 };
             }
 
-            population.push(Encoding { dimensions: self.dimensions,
-                                       polygons: polygons,
-                                       pixels: vec![] });
+            population.push(encoding);
         }
 
         population
     }
 
-    fn mutate(&self, population: Vec<Encoding>) -> (Vec<Encoding>, uint, uint) {
+    fn mutate(&self, population: Vec<Individual>) -> (Vec<Individual>, f64, uint) {
+        let mut rng = self.rng.borrow_mut();
         let mut new_population = vec![];
 
-        for candidate in population.into_iter() {
-            for _ in range(0, MUTATIONS) {
-                let mut candidate = candidate.clone();
+        for parent in population.iter() {
+            for _ in range(0, self.config.mutations) {
+                let (mut candidate, crossed) =
+                    if population.len() > 1 && should_mutate(&mut *rng, self.config.crossover_rate) {
+                        let other = &population[rng.gen::<uint>() % population.len()].encoding;
+                        let mut child = if rng.gen::<bool>() {
+                            crossover::spatial(&parent.encoding, other, &mut *rng)
+                        } else {
+                            crossover::splice(&parent.encoding, other, &mut *rng)
+                        };
+                        child.polygons.truncate(self.config.max_polygons);
+                        (child, true)
+                    } else {
+                        (parent.encoding.clone(), false)
+                    };
+
+                // everything a mutation touches, before and after, so only that
+                // area of the parent's render has to be redone
+                let mut dirty = Rect { x0: 0, y0: 0, x1: 0, y1: 0 };
 
                 let mut new_polygons = vec![];
                 for mut polygon in candidate.polygons.into_iter() {
-                    if should_mutate(REMOVE_POLYGON_RATE) { continue; }
-                    polygon.mutate(self);
+                    let before = footprint(&polygon, self.dimensions);
+                    if should_mutate(&mut *rng, self.config.remove_polygon_rate) {
+                        dirty = dirty.union(&before);
+                        continue;
+                    }
+
+                    if polygon.mutate(self, &mut *rng) {
+                        dirty = dirty.union(&before).union(&footprint(&polygon, self.dimensions));
+                    }
                     new_polygons.push(polygon);
                 }
 
                 candidate.polygons = new_polygons;
 
-                if should_mutate(ADD_POLYGON_RATE) && candidate.polygons.len() < MAX_POLYGONS {
-                    match Polygon::random(self) {
-                        Some(p) => { candidate.polygons.push(p); }
+                if should_mutate(&mut *rng, self.config.add_polygon_rate) &&
+                    candidate.polygons.len() < self.config.max_polygons {
+                    match Polygon::random(self, &mut *rng) {
+                        Some(p) => {
+                            dirty = dirty.union(&footprint(&p, self.dimensions));
+                            candidate.polygons.push(p);
+                        }
                         None => {}
                     }
                 }
 
-                new_population.push(Arc::new(Some(candidate)));
+                // render composites in vector order, so reordering fixes occlusion
+                // without throwing the polygons away
+                let len = candidate.polygons.len();
+                if len > 1 && should_mutate(&mut *rng, self.config.swap_polygon_rate) {
+                    let (i, j) = (rng.gen::<uint>() % len, rng.gen::<uint>() % len);
+                    if i != j {
+                        dirty = dirty.union(&footprint(&candidate.polygons[i], self.dimensions))
+                            .union(&footprint(&candidate.polygons[j], self.dimensions));
+                    }
+                    candidate.polygons.swap(i, j);
+                }
+
+                if len > 1 && should_mutate(&mut *rng, self.config.move_polygon_rate) {
+                    let polygon = candidate.polygons.remove(rng.gen::<uint>() % len).unwrap();
+                    dirty = dirty.union(&footprint(&polygon, self.dimensions));
+                    if rng.gen::<bool>() {
+                        candidate.polygons.push(polygon);
+                    } else {
+                        candidate.polygons.insert(0, polygon);
+                    }
+                }
+
+                // over the byte budget, the candidate isn't worth scoring at all
+                if self.over_budget(&candidate) {
+                    continue;
+                }
+
+                let damage = if crossed { Everything }
+                    else if dirty.is_empty() { Clean }
+                    else { Region(dirty) };
+
+                new_population.push(Arc::new(Offspring { encoding: candidate,
+                                                         parent_render: parent.render.clone(),
+                                                         parent_error: parent.error,
+                                                         damage: damage }));
             }
 
-            new_population.push(Arc::new(Some(candidate)));
+            new_population.push(Arc::new(Offspring { encoding: parent.encoding.clone(),
+                                                     parent_render: parent.render.clone(),
+                                                     parent_error: parent.error,
+                                                     damage: Clean }));
         }
 
-        let pool = TaskPool::new(WORKERS);
-        let (tmaster, rmaster) = channel();
-        for (i, individual) in new_population.iter().enumerate() {
-            let (tx, rx) = channel();
-            let tmaster = tmaster.clone();
-            pool.execute(proc() {
-                let (dimensions, base, downsampled, i, individual) = rx.recv();
-                tmaster.send((i, fitness(dimensions, base, downsampled, individual)));
-            });
+        let scored = self.score(new_population.as_slice());
 
-            tx.send((self.dimensions, self.base.clone(), self.downsampled.clone(), i, individual.clone()));
-        }
+        // sort_by is stable, which keeps ties (and the run) reproducible
+        let mut population_fitness: Vec<(uint, f64)> =
+            scored.iter().enumerate().map(|(i, &(error, _))| (i, error)).collect();
 
-        let mut population_fitness = vec![];
-        for _ in new_population.iter() {
-            population_fitness.push(rmaster.recv());
+        population_fitness.sort_by(|&(_, a), &(_, b)| a.partial_cmp(&b).unwrap_or(Equal));
+
+        let (best, min_fitness) = population_fitness[0];
+        let mut survivors = self.selection.select(population_fitness.as_slice(),
+                                                  self.config.population_size, &mut *rng);
+
+        // whatever the strategy, never lose the best individual, and keep it first
+        match survivors.iter().position(|&index| index == best) {
+            Some(i) => survivors.swap(0, i),
+            None => {
+                survivors.insert(0, best);
+                survivors.pop();
+            }
         }
 
-        population_fitness.sort_by(|&(_, a), &(_, b)| a.cmp(&b));
+        let filtered_population = survivors.iter().map(|&index| {
+            let (error, ref render) = scored[index];
+            Individual { encoding: new_population[index].encoding.clone(),
+                         render: render.clone(),
+                         error: error }
+        }).collect();
+
+        (filtered_population, min_fitness, 0)
+    }
 
-        let mut filtered_population = vec![];
-        let (mut min_fitness, mut min_individual) = (population_fitness[0].val1(), 0);
-        for i in range(0, POPULATION_SIZE) {
-            let (index, fitvalue) = population_fitness[i as uint];
-            if fitvalue < min_fitness {
-                min_fitness = fitvalue;
-                min_individual = index;
+    // Scores offspring in the worker pool. Anything identical to an encoding
+    // scored recently, or to another offspring, reuses that result instead of
+    // being rendered again.
+    fn score(&self, offspring: &[Arc<Offspring>]) -> Vec<(f64, Arc<Image>)> {
+        let mut cache = self.cache.borrow_mut();
+        let hasher = FnvHasher;
+        let mut scored = Vec::with_capacity(offspring.len());
+        let mut job_of = Vec::with_capacity(offspring.len());
+        let mut jobs = vec![];
+        let mut job_hashes = vec![];
+        let mut queued = HashMap::with_hasher(FnvHasher);
+
+        for child in offspring.iter() {
+            let hash = hasher.hash(&child.encoding);
+            let hit = cache.get(&hash).map(|&(error, ref render)| (error, render.clone()));
+            if hit.is_some() {
+                scored.push(hit);
+                job_of.push(0);
+                continue;
             }
 
-            filtered_population.push(new_population[index].make_unique().take().unwrap());
+            let existing = queued.get(&hash).map(|&job| job);
+            let job = match existing {
+                Some(job) => job,
+                None => {
+                    queued.insert(hash, jobs.len());
+                    jobs.push(child.clone());
+                    job_hashes.push(hash);
+                    jobs.len() - 1
+                }
+            };
+
+            scored.push(None);
+            job_of.push(job);
         }
 
-        (filtered_population, min_fitness, min_individual)
-    }
+        let start = time::precise_time_ns();
+        let results = self.pool.evaluate(jobs.as_slice());
+        let elapsed = (time::precise_time_ns() - start) as f64 / 1e9;
+        self.throughput.set(offspring.len() as f64 / elapsed);
+        debug!("Scored {} individuals ({} rendered) in {:.1}ms", offspring.len(), jobs.len(), elapsed * 1000.0);
 
-    pub fn max_score(&self) -> uint {
+        // every entry holds a full render, so the budget caps how many fit
         let (w, h) = self.dimensions;
-        fitness(
-            (w, h),
-            self.base.clone(),
-            self.downsampled.clone(),
-            Arc::new(Some(Encoding {
-                dimensions: (w, h),
-                polygons: vec![],
-                pixels: vec![] })))
+        let entries = self.config.cache_size * 1024 * 1024 / (w as uint * h as uint * 4);
+        if entries > 0 {
+            for ((&hash, result), job) in job_hashes.iter().zip(results.iter()).zip(jobs.iter()) {
+                // unchanged offspring share their parent's render and score already
+                match job.damage {
+                    Clean => continue,
+                    _ => {}
+                }
+
+                // forgetting everything at once is crude, but cheap and deterministic
+                if cache.len() >= entries {
+                    cache.clear();
+                }
+                cache.insert(hash, result.clone());
+            }
+        }
+
+        scored.into_iter().zip(job_of.into_iter()).map(|(hit, job)| {
+            match hit {
+                Some(result) => result,
+                None => results[job].clone(),
+            }
+        }).collect()
     }
 
+    // Renders and scores an encoding from scratch.
+    pub fn individual(&self, encoding: Encoding) -> Individual {
+        let render = render(&encoding, self.config.antialias);
+        Individual { error: self.fitness.error(&render),
+                     render: Arc::new(render),
+                     encoding: encoding }
+    }
 
+    pub fn max_score(&self) -> f64 {
+        self.individual(Encoding { dimensions: self.dimensions,
+                                   alpha: self.alpha,
+                                   polygons: vec![],
+                                   pixels: vec![] }).error
+    }
+
+    #[inline]
+    fn over_budget(&self, img: &Encoding) -> bool {
+        self.config.max_bytes != 0 && img.size() > self.config.max_bytes
+    }
+
+    // With a byte budget, only as many pixels as fit in what the polygons left
+    // over are fixed, worst first.
     pub fn fix_pixels(&self, mut img: Encoding) -> Encoding {
         let (w, h) = img.dimensions;
-        let new_render = render(&img, true);
+        let new_render = render(&img, self.config.antialias);
+        let threshold = self.config.pixel_fix_threshold.unwrap_or(self.fitness.pixel_threshold());
+        let mut wrong = vec![];
         for y in range(0, h) {
             for x in range(0, w) {
                 let i = (y * w + x) as uint;
-                let (br, bg, bb) = self.base[i];
-                let (nr, ng, nb) = new_render[i];
-                let score = 0.9 * diff(br, nr) as f32
-                    + 1.5 * diff(bg, ng) as f32
-                    + 0.6 * diff(bb, nb) as f32;
-
-                if score > PIXEL_FIX_THRESHOLD {
-                    img.pixels.push(Pixel {
-                        pos: Point { x: x as f32, y: y as f32 },
-                        color: (br, bg, bb)
-                    });
+                let score = self.fitness.pixel_error(self.base[i], new_render[i]);
+                if score > threshold {
+                    wrong.push((score, i));
                 }
             }
         }
 
-        info!("Fixed {} pixels", img.pixels.len());
+        let found = wrong.len();
+        if self.config.max_bytes != 0 {
+            let budget = if self.config.max_bytes > img.size() { self.config.max_bytes - img.size() } else { 0 };
+            wrong.sort_by(|&(a, _), &(b, _)| b.partial_cmp(&a).unwrap_or(Equal));
+            wrong.truncate(budget / format::pixel_size(img.alpha));
+            wrong.sort_by(|&(_, a), &(_, b)| a.cmp(&b));
+        }
+
+        for &(_, i) in wrong.iter() {
+            img.pixels.push(Pixel {
+                pos: Point { x: (i as u32 % w) as f32, y: (i as u32 / w) as f32 },
+                color: self.base[i]
+            });
+        }
+
+        info!("Fixed {} of {} pixels over the threshold", wrong.len(), found);
 
         img
     }
 
     pub fn compute_error(&mut self, img: &Encoding) {
         let (w, h) = img.dimensions;
-        let new_render = render(img, true);
-        self.error = Vec::from_fn(64, |_| 0);
+        let new_render = render(img, self.config.antialias);
+        self.error = Vec::from_elem((ERROR_GRID * ERROR_GRID) as uint, 0.0);
         let mut imgbuf = ImageBuf::new(w, h);
 
         for y in range(0, h) {
             for x in range(0, w) {
                 let i = (y * w + x) as uint;
-                let (br, bg, bb) = self.base[i];
-                let (nr, ng, nb) = new_render[i];
-                let score = diff(br, nr) + diff(bg, ng) + diff(bb, nb);
+                let score = self.fitness.pixel_error(self.base[i], new_render[i]);
 
-                self.error[(x / 25 + y / 25 * 8) as uint] += score;
+                let region = self.error_region(x, y);
+                self.error[region] += score;
             }
         }
 
-        let max_score = self.error.iter().fold(1, |b, a| max(*a, b)) as f32;
+        let max_score = fmax(self.error.iter().fold(0.0, |b, a| fmax(*a, b)), Float::epsilon());
 
         for y in range(0, h) {
             for x in range(0, w) {
-                let luma = image::Luma((self.error[(x / 25 + y / 25 * 8) as uint] as f32 / max_score * 255.0) as u8);
+                let luma = image::Luma((self.error[self.error_region(x, y)] / max_score * 255.0) as u8);
                 imgbuf.put_pixel(x, y, luma);
             }
         }
 
-        image::ImageLuma8(imgbuf).save(File::create(&Path::new("error.png")).unwrap(),
-                                       image::PNG);
+        if self.config.debug_images {
+            image::ImageLuma8(imgbuf).save(File::create(&Path::new("error.png")).unwrap(),
+                                           image::PNG);
+        }
      }
 
-    fn color_sum(&self, (r, g, b): (u8, u8, u8)) -> f32 {
-        (r as f32) + (g as f32) + (b as f32)
+    #[inline]
+    pub fn error_region(&self, x: u32, y: u32) -> uint {
+        let (cw, ch) = self.cell_size;
+        (x / cw + y / ch * ERROR_GRID) as uint
+    }
+
+    // Top-left corner and size of a cell of the error map.
+    pub fn error_cell(&self, region: uint) -> (Point, Point) {
+        let (cw, ch) = self.cell_size;
+        let (x, y) = (region as u32 % ERROR_GRID, region as u32 / ERROR_GRID);
+        (Point {x: (x * cw) as f32, y: (y * ch) as f32},
+         Point {x: cw as f32, y: ch as f32})
+    }
+
+    fn color_sum(&self, color: Color) -> f32 {
+        let (r, g, b, a) = premultiply(color);
+        (r as f32) + (g as f32) + (b as f32) + (a as f32)
     }
 
     pub fn detect_edges(&mut self) {
@@ -335,7 +713,9 @@ impl Compressor {
             }
         }*/
 
-        image::ImageLuma8(imgbuf).save(File::create(&Path::new("edges.png")).unwrap(),
-                                       image::PNG);
+        if self.config.debug_images {
+            image::ImageLuma8(imgbuf).save(File::create(&Path::new("edges.png")).unwrap(),
+                                           image::PNG);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,355 @@
+use std::default::Default;
+use std::from_str::FromStr;
+use std::io::{File, BufferedReader};
+use std::rand::Rng;
+
+use constants::*;
+use selection::{SelectionStrategy, Truncation};
+use fitness::{FitnessMetric, L1};
+use format;
+
+// All rates are "1 in N" chances per opportunity; a rate of 0 disables the mutation.
+#[deriving(Clone, Show)]
+pub struct CompressorConfig {
+    /// Stop once the best individual reaches this score (between 0 and 1).
+    pub threshold: f32,
+    /// Stop after this many generations, or never if 0.
+    pub iterations: uint,
+    /// Stop after this many seconds, or never if 0. The best encoding so far is kept.
+    pub time_limit: f64,
+    /// Treat the run as stuck once its score improves by no more than `plateau_epsilon`
+    /// over this many generations. 0 disables plateau detection.
+    pub plateau_window: uint,
+    pub plateau_epsilon: f32,
+    /// How many times a stuck run restarts from perturbed copies of its best
+    /// individual before it stops.
+    pub plateau_restarts: uint,
+    /// Never let the encoding grow past this many bytes, fixed pixels included.
+    /// 0 means no limit.
+    pub max_bytes: uint,
+    /// Write `edges.png` and `error.png` to the working directory while compressing.
+    pub debug_images: bool,
+    /// Seed for all randomness in a run. The same seed and worker count give the
+    /// same encoding; if unset, a seed is picked and logged.
+    pub seed: Option<u64>,
+    /// Periodically save the run here, so it can be resumed after a crash.
+    pub checkpoint_path: Option<String>,
+    /// Generations between checkpoints.
+    pub checkpoint_interval: uint,
+
+    /// Fix pixels whose error is above this. Defaults to a threshold suited to the fitness metric.
+    pub pixel_fix_threshold: Option<f32>,
+    pub initial_polygons: uint,
+    pub workers: uint,
+    /// Megabytes of scored encodings to remember, so identical offspring aren't
+    /// rendered again. Each one keeps a full render alive, 4 bytes per pixel, so
+    /// large images fit fewer. 0 disables the cache.
+    pub cache_size: uint,
+    /// Samples per pixel row used to antialias polygon edges, both while compressing
+    /// and in the final render, so the two agree. 0 draws aliased edges.
+    pub antialias: uint,
+    pub mutations: uint,
+    pub population_size: uint,
+    pub vertices: uint,
+    pub poly_size_init: f32,
+
+    pub add_polygon_rate: uint,
+    pub max_polygons: uint,
+    pub remove_polygon_rate: uint,
+
+    /// Chance of swapping the z-order of two polygons.
+    pub swap_polygon_rate: uint,
+    /// Chance of moving a polygon to the top or bottom of the z-order.
+    pub move_polygon_rate: uint,
+
+    /// Chance that an offspring is bred from two parents instead of cloned from one.
+    pub crossover_rate: uint,
+
+    /// How renders are scored: l1, ssim, ms-ssim, lab or pyramid.
+    pub fitness: FitnessMetric,
+    /// Number of halved resolutions the pyramid metric scores besides the full image.
+    pub pyramid_levels: uint,
+    /// How much more each coarser pyramid level counts than the one above it.
+    pub pyramid_weight: f32,
+
+    /// How survivors are picked: truncation, tournament, rank or roulette.
+    pub selection: SelectionStrategy,
+    pub tournament_size: uint,
+
+    pub change_color_rate: uint,
+    pub change_color_max: f32,
+
+    pub move_vertex_rate: uint,
+    pub move_vertex_max: f32,
+
+    pub add_vertex_rate: uint,
+    pub remove_vertex_rate: uint,
+
+    pub change_blur_rate: uint,
+    /// Largest blur, in pixels, a mutation gives a polygon's edges.
+    pub max_blur: f32,
+}
+
+impl Default for CompressorConfig {
+    fn default() -> CompressorConfig {
+        CompressorConfig {
+            threshold: FITNESS_THRESHOLD,
+            iterations: 0,
+            time_limit: 0.0,
+            plateau_window: 0,
+            plateau_epsilon: PLATEAU_EPSILON,
+            plateau_restarts: 0,
+            max_bytes: 0,
+            debug_images: false,
+            seed: None,
+            checkpoint_path: None,
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+
+            pixel_fix_threshold: None,
+            initial_polygons: INITIAL_POLYGONS,
+            workers: WORKERS,
+            cache_size: CACHE_SIZE,
+            antialias: ANTIALIAS,
+            mutations: MUTATIONS,
+            population_size: POPULATION_SIZE,
+            vertices: VERTICES,
+            poly_size_init: POLY_SIZE_INIT,
+
+            add_polygon_rate: ADD_POLYGON_RATE,
+            max_polygons: MAX_POLYGONS,
+            remove_polygon_rate: REMOVE_POLYGON_RATE,
+
+            swap_polygon_rate: SWAP_POLYGON_RATE,
+            move_polygon_rate: MOVE_POLYGON_RATE,
+
+            crossover_rate: CROSSOVER_RATE,
+
+            fitness: L1,
+            pyramid_levels: PYRAMID_LEVELS,
+            pyramid_weight: PYRAMID_WEIGHT,
+
+            selection: Truncation,
+            tournament_size: TOURNAMENT_SIZE,
+
+            change_color_rate: CHANGE_COLOR_RATE,
+            change_color_max: CHANGE_COLOR_MAX,
+
+            move_vertex_rate: MOVE_VERTEX_RATE,
+            move_vertex_max: MOVE_VERTEX_MAX,
+
+            add_vertex_rate: ADD_VERTEX_RATE,
+            remove_vertex_rate: REMOVE_VERTEX_RATE,
+
+            change_blur_rate: CHANGE_BLUR_RATE,
+            max_blur: MAX_BLUR,
+        }
+    }
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    match from_str(value) {
+        Some(v) => Ok(v),
+        None => Err(format!("invalid value `{}` for `{}`", value, key)),
+    }
+}
+
+impl CompressorConfig {
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "threshold" => self.threshold = try!(parse(key, value)),
+            "iterations" => self.iterations = try!(parse(key, value)),
+            "time_limit" => self.time_limit = try!(parse(key, value)),
+            "plateau_window" => self.plateau_window = try!(parse(key, value)),
+            "plateau_epsilon" => self.plateau_epsilon = try!(parse(key, value)),
+            "plateau_restarts" => self.plateau_restarts = try!(parse(key, value)),
+            "max_bytes" => self.max_bytes = try!(parse(key, value)),
+            "debug_images" => self.debug_images = try!(parse(key, value)),
+            "seed" => self.seed = Some(try!(parse(key, value))),
+            "checkpoint_path" => self.checkpoint_path = Some(value.to_string()),
+            "checkpoint_interval" => self.checkpoint_interval = try!(parse(key, value)),
+
+            "pixel_fix_threshold" => self.pixel_fix_threshold = Some(try!(parse(key, value))),
+            "initial_polygons" => self.initial_polygons = try!(parse(key, value)),
+            "workers" => self.workers = try!(parse(key, value)),
+            "cache_size" => self.cache_size = try!(parse(key, value)),
+            "antialias" => self.antialias = try!(parse(key, value)),
+            "mutations" => self.mutations = try!(parse(key, value)),
+            "population_size" => self.population_size = try!(parse(key, value)),
+            "vertices" => self.vertices = try!(parse(key, value)),
+            "poly_size_init" => self.poly_size_init = try!(parse(key, value)),
+
+            "add_polygon_rate" => self.add_polygon_rate = try!(parse(key, value)),
+            "max_polygons" => self.max_polygons = try!(parse(key, value)),
+            "remove_polygon_rate" => self.remove_polygon_rate = try!(parse(key, value)),
+
+            "swap_polygon_rate" => self.swap_polygon_rate = try!(parse(key, value)),
+            "move_polygon_rate" => self.move_polygon_rate = try!(parse(key, value)),
+
+            "crossover_rate" => self.crossover_rate = try!(parse(key, value)),
+
+            "fitness" => self.fitness = try!(parse(key, value)),
+            "pyramid_levels" => self.pyramid_levels = try!(parse(key, value)),
+            "pyramid_weight" => self.pyramid_weight = try!(parse(key, value)),
+
+            "selection" => self.selection = try!(parse(key, value)),
+            "tournament_size" => self.tournament_size = try!(parse(key, value)),
+
+            "change_color_rate" => self.change_color_rate = try!(parse(key, value)),
+            "change_color_max" => self.change_color_max = try!(parse(key, value)),
+
+            "move_vertex_rate" => self.move_vertex_rate = try!(parse(key, value)),
+            "move_vertex_max" => self.move_vertex_max = try!(parse(key, value)),
+
+            "add_vertex_rate" => self.add_vertex_rate = try!(parse(key, value)),
+            "remove_vertex_rate" => self.remove_vertex_rate = try!(parse(key, value)),
+
+            "change_blur_rate" => self.change_blur_rate = try!(parse(key, value)),
+            "max_blur" => self.max_blur = try!(parse(key, value)),
+
+            _ => return Err(format!("unknown option `{}`", key)),
+        }
+
+        Ok(())
+    }
+
+    // Applies a `key = value` assignment, as given to `--set` or written in a config file.
+    pub fn set_assignment(&mut self, assignment: &str) -> Result<(), String> {
+        match assignment.find('=') {
+            Some(i) => self.set(assignment.slice_to(i).trim(), assignment.slice_from(i + 1).trim()),
+            None => Err(format!("expected `key = value`, found `{}`", assignment)),
+        }
+    }
+
+    // Every setting as a `key = value` line, in the format `load` reads.
+    pub fn assignments(&self) -> String {
+        let mut lines = vec![
+            format!("threshold = {}", self.threshold),
+            format!("iterations = {}", self.iterations),
+            format!("time_limit = {}", self.time_limit),
+            format!("plateau_window = {}", self.plateau_window),
+            format!("plateau_epsilon = {}", self.plateau_epsilon),
+            format!("plateau_restarts = {}", self.plateau_restarts),
+            format!("max_bytes = {}", self.max_bytes),
+            format!("debug_images = {}", self.debug_images),
+            format!("checkpoint_interval = {}", self.checkpoint_interval),
+
+            format!("initial_polygons = {}", self.initial_polygons),
+            format!("workers = {}", self.workers),
+            format!("cache_size = {}", self.cache_size),
+            format!("antialias = {}", self.antialias),
+            format!("mutations = {}", self.mutations),
+            format!("population_size = {}", self.population_size),
+            format!("vertices = {}", self.vertices),
+            format!("poly_size_init = {}", self.poly_size_init),
+
+            format!("add_polygon_rate = {}", self.add_polygon_rate),
+            format!("max_polygons = {}", self.max_polygons),
+            format!("remove_polygon_rate = {}", self.remove_polygon_rate),
+
+            format!("swap_polygon_rate = {}", self.swap_polygon_rate),
+            format!("move_polygon_rate = {}", self.move_polygon_rate),
+
+            format!("crossover_rate = {}", self.crossover_rate),
+
+            format!("fitness = {}", self.fitness.name()),
+            format!("pyramid_levels = {}", self.pyramid_levels),
+            format!("pyramid_weight = {}", self.pyramid_weight),
+
+            format!("selection = {}", self.selection.name()),
+            format!("tournament_size = {}", self.tournament_size),
+
+            format!("change_color_rate = {}", self.change_color_rate),
+            format!("change_color_max = {}", self.change_color_max),
+
+            format!("move_vertex_rate = {}", self.move_vertex_rate),
+            format!("move_vertex_max = {}", self.move_vertex_max),
+
+            format!("add_vertex_rate = {}", self.add_vertex_rate),
+            format!("remove_vertex_rate = {}", self.remove_vertex_rate),
+
+            format!("change_blur_rate = {}", self.change_blur_rate),
+            format!("max_blur = {}", self.max_blur),
+        ];
+
+        match self.seed {
+            Some(seed) => lines.push(format!("seed = {}", seed)),
+            None => {}
+        }
+        match self.checkpoint_path {
+            Some(ref path) => lines.push(format!("checkpoint_path = {}", path)),
+            None => {}
+        }
+        match self.pixel_fix_threshold {
+            Some(threshold) => lines.push(format!("pixel_fix_threshold = {}", threshold)),
+            None => {}
+        }
+
+        lines.connect("\n")
+    }
+
+    // Applies every assignment in a config file. Blank lines and `#` comments are ignored.
+    pub fn load(&mut self, path: &Path) -> Result<(), String> {
+        let mut file = match File::open(path) {
+            Ok(f) => BufferedReader::new(f),
+            Err(err) => return Err(format!("{}: {}", path.display(), err)),
+        };
+
+        for (n, line) in file.lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Err(format!("{}: {}", path.display(), err)),
+            };
+
+            let line = match line.as_slice().find('#') {
+                Some(i) => line.as_slice().slice_to(i),
+                None => line.as_slice(),
+            }.trim();
+
+            if line.is_empty() { continue; }
+
+            match self.set_assignment(line) {
+                Ok(()) => {},
+                Err(err) => return Err(format!("{}:{}: {}", path.display(), n + 1, err)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Whether renders are scored the same way under both configs, so that errors
+    // computed under one can be compared with errors computed under the other.
+    pub fn scores_like(&self, other: &CompressorConfig) -> bool {
+        self.fitness == other.fitness && self.antialias == other.antialias &&
+            self.pyramid_levels == other.pyramid_levels && self.pyramid_weight == other.pyramid_weight
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.population_size == 0 {
+            return Err("population_size must be at least 1".to_string());
+        }
+        if self.workers == 0 {
+            return Err("workers must be at least 1".to_string());
+        }
+        if self.vertices < 3 {
+            return Err("vertices must be at least 3".to_string());
+        }
+        if self.tournament_size == 0 {
+            return Err("tournament_size must be at least 1".to_string());
+        }
+        // .gpe files store it in a byte
+        if self.antialias > 255 {
+            return Err("antialias must be at most 255".to_string());
+        }
+        // even an encoding without polygons needs its header
+        if self.max_bytes != 0 && self.max_bytes < format::HEADER_SIZE {
+            return Err(format!("max_bytes must be at least {}", format::HEADER_SIZE));
+        }
+
+        Ok(())
+    }
+}
+
+#[inline(always)]
+pub fn should_mutate<R: Rng>(rng: &mut R, max: uint) -> bool {
+    max != 0 && (rng.gen::<uint>() % max) == 0
+}
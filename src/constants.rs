@@ -1,9 +1,14 @@
-use std::rand::random;
+// Defaults for `CompressorConfig`.
 
 pub static FITNESS_THRESHOLD: f32 = 0.95;
 pub static PIXEL_FIX_THRESHOLD: f32 = 50.0;
+pub static DELTA_E_FIX_THRESHOLD: f32 = 12.0;
 pub static INITIAL_POLYGONS: uint = 0;
 pub static WORKERS: uint = 16;
+pub static CACHE_SIZE: uint = 64;
+pub static ANTIALIAS: uint = 4;
+pub static CHECKPOINT_INTERVAL: uint = 100;
+pub static PLATEAU_EPSILON: f32 = 0.0001;
 pub static MUTATIONS: uint = 1;
 pub static POPULATION_SIZE: uint = 16;
 pub static VERTICES: uint = 5;
@@ -13,6 +18,16 @@ pub static ADD_POLYGON_RATE: uint = 15;
 pub static MAX_POLYGONS: uint = 100;
 pub static REMOVE_POLYGON_RATE: uint = 80;
 
+pub static SWAP_POLYGON_RATE: uint = 30;
+pub static MOVE_POLYGON_RATE: uint = 30;
+
+pub static CROSSOVER_RATE: uint = 5;
+
+pub static TOURNAMENT_SIZE: uint = 3;
+
+pub static PYRAMID_LEVELS: uint = 3;
+pub static PYRAMID_WEIGHT: f32 = 2.0;
+
 pub static CHANGE_COLOR_RATE: uint = 40;
 pub static CHANGE_COLOR_MAX: f32 = 150.0;
 
@@ -23,8 +38,4 @@ pub static ADD_VERTEX_RATE: uint = 40;
 pub static REMOVE_VERTEX_RATE: uint = 80;
 
 pub static CHANGE_BLUR_RATE: uint = 60;
-
-#[inline(always)]
-pub fn should_mutate(max: uint) -> bool {
-    (random::<uint>() % max) == 1
-}
+pub static MAX_BLUR: f32 = 4.0;
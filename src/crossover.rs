@@ -0,0 +1,54 @@
+use std::rand::Rng;
+
+use encoding::{Encoding, Polygon};
+
+#[inline]
+fn before_split(polygon: &Polygon, horizontal: bool, split: f32) -> bool {
+    let (min, max) = polygon.bounding_box;
+    let center = (min + max) * 0.5;
+    (if horizontal { center.y } else { center.x }) < split
+}
+
+// Merges two polygon lists by their relative depth in their parent, so that
+// shapes near the top of either parent stay near the top of the child.
+fn merge(a: Vec<(f32, Polygon)>, b: Vec<(f32, Polygon)>) -> Vec<Polygon> {
+    let mut layers: Vec<(f32, Polygon)> = a.into_iter().chain(b.into_iter()).collect();
+    layers.sort_by(|&(da, _), &(db, _)| if da < db { Less } else if da > db { Greater } else { Equal });
+    layers.into_iter().map(|(_, polygon)| polygon).collect()
+}
+
+fn layers(img: &Encoding, keep: |&Polygon| -> bool) -> Vec<(f32, Polygon)> {
+    let len = img.polygons.len() as f32;
+    img.polygons.iter().enumerate()
+        .filter(|&(_, polygon)| keep(polygon))
+        .map(|(i, polygon)| (i as f32 / len, polygon.clone()))
+        .collect()
+}
+
+// Cuts the image along a random horizontal or vertical line and takes the
+// polygons centered on one side from `a` and those on the other side from `b`.
+pub fn spatial<R: Rng>(a: &Encoding, b: &Encoding, rng: &mut R) -> Encoding {
+    let (w, h) = a.dimensions;
+    let horizontal = rng.gen::<bool>();
+    let split = rng.gen::<f32>() * (if horizontal { h } else { w }) as f32;
+
+    Encoding { dimensions: a.dimensions,
+               alpha: a.alpha,
+               polygons: merge(layers(a, |p| before_split(p, horizontal, split)),
+                               layers(b, |p| !before_split(p, horizontal, split))),
+               pixels: vec![] }
+}
+
+// Takes the bottom of `a`'s polygon stack and the top of `b`'s.
+pub fn splice<R: Rng>(a: &Encoding, b: &Encoding, rng: &mut R) -> Encoding {
+    let cut_a = rng.gen::<uint>() % (a.polygons.len() + 1);
+    let cut_b = rng.gen::<uint>() % (b.polygons.len() + 1);
+
+    let mut polygons = a.polygons.slice_to(cut_a).to_vec();
+    polygons.push_all(b.polygons.slice_from(cut_b));
+
+    Encoding { dimensions: a.dimensions,
+               alpha: a.alpha,
+               polygons: polygons,
+               pixels: vec![] }
+}
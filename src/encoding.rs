@@ -1,11 +1,15 @@
 use std::fmt;
+use std::hash::{Hash, Writer};
+use std::mem::transmute;
 use std::num::Float;
-use std::rand::random;
+use std::rand::Rng;
 use std::cmp::{min, max};
 use std::iter::range_inclusive;
+use std::u32;
 
-use constants::*;
 use compress::Compressor;
+use config::{CompressorConfig, should_mutate};
+use format;
 
 #[deriving(Clone)]
 pub struct Point {
@@ -18,13 +22,14 @@ pub type Color = (u8, u8, u8, u8);
 #[deriving(Clone)]
 pub struct Pixel {
     pub pos: Point,
-    pub color: (u8, u8, u8),
+    pub color: Color,
 }
 
 #[deriving(Clone)]
 pub struct Polygon {
     pub vertices: Vec<Point>,
     pub color: Color,
+    // Standard deviation, in pixels, of the gaussian its edges are blurred by.
     pub blur: f32,
     edges: Vec<(Point, Point)>,
     center: Point,
@@ -36,9 +41,51 @@ pub struct Polygon {
 pub struct Encoding {
     pub polygons: Vec<Polygon>,
     pub dimensions: (u32, u32),
+    // Whether the image has transparency. Opaque encodings render onto white,
+    // transparent ones onto a fully transparent canvas.
+    pub alpha: bool,
     pub pixels: Vec<Pixel>,
 }
 
+// Encodings hash everything that affects their render, bit for bit, so equal
+// hashes mean (barring collisions) identical renders and scores.
+#[inline(always)]
+fn hash_f32<S: Writer>(v: f32, state: &mut S) {
+    let bits: u32 = unsafe { transmute(v) };
+    bits.hash(state);
+}
+
+impl<S: Writer> Hash<S> for Point {
+    fn hash(&self, state: &mut S) {
+        hash_f32(self.x, state);
+        hash_f32(self.y, state);
+    }
+}
+
+impl<S: Writer> Hash<S> for Pixel {
+    fn hash(&self, state: &mut S) {
+        self.pos.hash(state);
+        self.color.hash(state);
+    }
+}
+
+impl<S: Writer> Hash<S> for Polygon {
+    fn hash(&self, state: &mut S) {
+        self.vertices.hash(state);
+        self.color.hash(state);
+        hash_f32(self.blur, state);
+    }
+}
+
+impl<S: Writer> Hash<S> for Encoding {
+    fn hash(&self, state: &mut S) {
+        self.dimensions.hash(state);
+        self.alpha.hash(state);
+        self.polygons.hash(state);
+        self.pixels.hash(state);
+    }
+}
+
 #[inline(always)]
 pub fn fmin(a: f32, b: f32) -> f32 {
     if a < b { a } else { b }
@@ -49,6 +96,17 @@ pub fn fmax(a: f32, b: f32) -> f32 {
     if a < b { b } else { a }
 }
 
+// Squared distance from `pt` to the segment from `a` to `b`.
+#[inline(always)]
+pub fn edge_distance_squared(pt: &Point, a: Point, b: Point) -> f32 {
+    let ba = b - a;
+    let mag = a.distance_squared(&b);
+    let t = (*pt - a).dot(&ba) / mag;
+    if t < 0.0 { pt.distance_squared(&a) }
+    else if t > 1.0 { pt.distance_squared(&b) }
+    else { pt.distance_squared(&(a + ba * t)) }
+}
+
 #[inline(always)]
 fn clamp(p: &mut Point, (w, h): (u32, u32)) {
     p.x = fmax(fmin(p.x, (w - 1) as f32), 0.0);
@@ -68,11 +126,11 @@ impl Point {
     }
 
     #[inline]
-    pub fn mutate(&mut self, (w, h): (u32, u32)) -> bool {
-        let mutated = should_mutate(MOVE_VERTEX_RATE);
+    pub fn mutate<R: Rng>(&mut self, rng: &mut R, config: &CompressorConfig, (w, h): (u32, u32)) -> bool {
+        let mutated = should_mutate(rng, config.move_vertex_rate);
         if mutated {
-            self.x += (random::<f32>() - 0.5) * MOVE_VERTEX_MAX;
-            self.y += (random::<f32>() - 0.5) * MOVE_VERTEX_MAX;
+            self.x += (rng.gen::<f32>() - 0.5) * config.move_vertex_max;
+            self.y += (rng.gen::<f32>() - 0.5) * config.move_vertex_max;
             clamp(self, (w, h));
         }
 
@@ -142,29 +200,32 @@ impl Polygon {
         polygon
     }
 
-    pub fn random(compressor: &Compressor) -> Option<Polygon> {
+    pub fn random<R: Rng>(compressor: &Compressor, rng: &mut R) -> Option<Polygon> {
         let (w, h) = compressor.dimensions;
+        let config = &compressor.config;
         let mut vertices = vec![];
 
-        if random::<f32>() < 0.7 && compressor.error.iter().fold(0, |b, a| b + *a) > 0 {
-            let mut regions: Vec<(uint, &uint)> = compressor.error.iter().enumerate().collect();
-            regions.sort_by(|&(_, a), &(_, b)| b.cmp(a));
-            let (region, _) = regions[random::<uint>() % 8];
-
-            let (x, y) = ((region % 8) as f32 * 25.0, (region / 8) as f32 * 25.0);
+        if rng.gen::<f32>() < 0.7 && compressor.error.iter().fold(0.0, |b, a| b + *a) > 0.0 {
+            let mut regions: Vec<(uint, &f32)> = compressor.error.iter().enumerate().collect();
+            regions.sort_by(|&(_, a), &(_, b)| b.partial_cmp(a).unwrap_or(Equal));
+            let (region, _) = regions[rng.gen::<uint>() % 8];
+            let (corner, size) = compressor.error_cell(region);
 
             let mut eligible_pts = vec![];
             for &(pt, _, _) in compressor.edges.iter() {
-                if pt.x >= x && pt.x <= x + 25.0 && pt.y >= y && pt.y <= y + 25.0 {
+                if pt.x >= corner.x && pt.x <= corner.x + size.x &&
+                    pt.y >= corner.y && pt.y <= corner.y + size.y {
                     eligible_pts.push(pt);
                 }
             }
 
-            let origin = if eligible_pts.len() == 0 || random::<f32>() < 0.5 {
-                Point {x: (x as f32) * 25.0 + random::<f32>() * 25.0,
-                       y: (y as f32) * 25.0 + random::<f32>() * 25.0}
+            let origin = if eligible_pts.len() == 0 || rng.gen::<f32>() < 0.5 {
+                let mut origin = Point {x: corner.x + rng.gen::<f32>() * size.x,
+                                        y: corner.y + rng.gen::<f32>() * size.y};
+                clamp(&mut origin, (w, h));
+                origin
             } else {
-                eligible_pts[random::<uint>() % eligible_pts.len()]
+                eligible_pts[rng.gen::<uint>() % eligible_pts.len()]
             };
 
             let mut edges = compressor.edges.clone();
@@ -174,54 +235,55 @@ impl Polygon {
             });
 
             vertices.push(origin);
-            if random::<f32>() < 0.5 {
-                for _ in range(0, VERTICES - 1) {
-                    let mut vtx = origin + Point{x: (random::<f32>() - 0.5) * POLY_SIZE_INIT,
-                                                 y: (random::<f32>() - 0.5) * POLY_SIZE_INIT};
+            if edges.len() == 0 || rng.gen::<f32>() < 0.5 {
+                for _ in range(0, config.vertices - 1) {
+                    let mut vtx = origin + Point{x: (rng.gen::<f32>() - 0.5) * config.poly_size_init,
+                                                 y: (rng.gen::<f32>() - 0.5) * config.poly_size_init};
                     clamp(&mut vtx, (w, h));
                     vertices.push(vtx);
                 }
             } else {
-                for _ in range(0, VERTICES - 1) {
-                    let (pt, _ , _) = edges[random::<uint>() % (min(50, edges.len()))];
+                for _ in range(0, config.vertices - 1) {
+                    let (pt, _ , _) = edges[rng.gen::<uint>() % (min(50, edges.len()))];
                     vertices.push(pt);
                 }
             }
 
-        } else {
+        } else if compressor.edges.len() > 0 {
             let len = compressor.edges.len();
-            for _ in range(0, VERTICES) {
-                let (vtx, _, _) = compressor.edges[random::<uint>() % len];
+            for _ in range(0, config.vertices) {
+                let (vtx, _, _) = compressor.edges[rng.gen::<uint>() % len];
                 vertices.push(vtx);
             }
         }
 
         let (w, h) = compressor.dimensions;
-        let origin = if compressor.error.iter().fold(0, |b, a| b + *a) > 0 {
-            let mut regions: Vec<(uint, &uint)> = compressor.error.iter().enumerate().collect();
-            regions.sort_by(|&(_, a), &(_, b)| b.cmp(a));
-            let (region, _) = regions[random::<uint>() % 4];
-
-            let (x, y) = (region % 8, region / 8);
-
-            Point {x: (x as f32) * 25.0 + random::<f32>() * 25.0,
-                   y: (y as f32) * 25.0 + random::<f32>() * 25.0}
+        let origin = if compressor.error.iter().fold(0.0, |b, a| b + *a) > 0.0 {
+            let mut regions: Vec<(uint, &f32)> = compressor.error.iter().enumerate().collect();
+            regions.sort_by(|&(_, a), &(_, b)| b.partial_cmp(a).unwrap_or(Equal));
+            let (region, _) = regions[rng.gen::<uint>() % 4];
+            let (corner, size) = compressor.error_cell(region);
+
+            let mut origin = Point {x: corner.x + rng.gen::<f32>() * size.x,
+                                    y: corner.y + rng.gen::<f32>() * size.y};
+            clamp(&mut origin, (w, h));
+            origin
         } else {
-            Point {x: random::<f32>() * (w as f32),
-                   y: random::<f32>() * (h as f32)}
+            Point {x: rng.gen::<f32>() * (w as f32),
+                   y: rng.gen::<f32>() * (h as f32)}
         };
 
 
         let mut vertices = vec![origin];
-        for _ in range(0, VERTICES - 1) {
-            let mut vtx = origin + Point{x: (random::<f32>() - 0.5) * POLY_SIZE_INIT,
-                                         y: (random::<f32>() - 0.5) * POLY_SIZE_INIT};
+        for _ in range(0, config.vertices - 1) {
+            let mut vtx = origin + Point{x: (rng.gen::<f32>() - 0.5) * config.poly_size_init,
+                                         y: (rng.gen::<f32>() - 0.5) * config.poly_size_init};
             clamp(&mut vtx, (w, h));
             vertices.push(vtx);
         }
 
         let mut polygon = Polygon::new(order_points(vertices), (0, 0, 0, 0));
-        let (mut r, mut g, mut b) = (0, 0, 0);
+        let (mut r, mut g, mut b, mut a) = (0, 0, 0, 0);
         let mut count = 0u;
         let (bbmin, bbmax) = polygon.bounding_box;
 
@@ -232,12 +294,13 @@ impl Polygon {
 
                 let pt = Point {x: x as f32, y: y as f32};
                 let (contains, _) = polygon.query(&pt, false);
-                let (br, bg, bb) = compressor.base[((y * w) + x) as uint];
+                let (br, bg, bb, ba) = compressor.base[((y * w) + x) as uint];
                 if contains {
                     count += 1;
                     r += br as uint;
                     g += bg as uint;
                     b += bb as uint;
+                    a += ba as uint;
                 }
             }
         }
@@ -247,12 +310,14 @@ impl Polygon {
             return None;
         }
 
-        polygon.color = (polygon.rand_color((r / count) as u8),
-                         polygon.rand_color((g / count) as u8),
-                         polygon.rand_color((b / count) as u8),
-                         random::<u8>() % 130 + 125);
-
-        polygon.blur = 0.5 + random::<f32>() * 0.5;
+        polygon.color = (polygon.rand_color(rng, config, (r / count) as u8),
+                         polygon.rand_color(rng, config, (g / count) as u8),
+                         polygon.rand_color(rng, config, (b / count) as u8),
+                         if compressor.alpha {
+                             polygon.rand_color(rng, config, (a / count) as u8)
+                         } else {
+                             rng.gen::<u8>() % 130 + 125
+                         });
 
         Some(polygon)
     }
@@ -277,9 +342,8 @@ impl Polygon {
             max_dist = if vdist > max_dist { vdist } else { max_dist };
         }
 
-        // TODO: actual w, h
         let (mut minx, mut miny, mut maxx, mut maxy) =
-            (200 - 1, 200 - 1, 0, 0);
+            (u32::MAX, u32::MAX, 0, 0);
 
         for vertex in self.vertices.iter() {
             minx = min(minx, vertex.x as u32);
@@ -295,6 +359,11 @@ impl Polygon {
                              Point {x: maxx as f32, y: maxy as f32});
     }
 
+    #[inline]
+    pub fn edges(&self) -> &[(Point, Point)] {
+        self.edges.as_slice()
+    }
+
     #[inline]
     pub fn query(&self, pt: &Point, antialias: bool) -> (bool, f32) {
         let mut inside = false;
@@ -307,13 +376,7 @@ impl Polygon {
             }
 
             if antialias {
-                let ba = b - a;
-                let mag = a.distance_squared(&b);
-                let t = (*pt - a).dot(&ba) / mag;
-                let dist = if t < 0.0 { pt.distance_squared(&a) }
-                else if t > 1.0 { pt.distance_squared(&b) }
-                else { pt.distance_squared(&(a + ba * t)) };
-
+                let dist = edge_distance_squared(pt, a, b);
                 min_dist = if dist < min_dist { dist } else { min_dist };
             }
         }
@@ -322,43 +385,65 @@ impl Polygon {
     }
 
     #[inline]
-    fn rand_color(&self, base: u8) -> u8 {
-        if should_mutate(CHANGE_COLOR_RATE) {
-            min(max((base as f32 + (random::<f32>() - 0.5) * CHANGE_COLOR_MAX) as uint, 0), 255) as u8
+    fn rand_color<R: Rng>(&self, rng: &mut R, config: &CompressorConfig, base: u8) -> u8 {
+        if should_mutate(rng, config.change_color_rate) {
+            min(max((base as f32 + (rng.gen::<f32>() - 0.5) * config.change_color_max) as uint, 0), 255) as u8
         } else {
             base
         }
     }
 
-    pub fn mutate(&mut self, compressor: &Compressor) {
+    // Returns whether the polygon changed at all.
+    pub fn mutate<R: Rng>(&mut self, compressor: &Compressor, rng: &mut R) -> bool {
+        let config = &compressor.config;
+        let old_color = self.color;
         let (mut r, mut g, mut b, mut a) = self.color;
-        r = self.rand_color(r);
-        g = self.rand_color(g);
-        b = self.rand_color(b);
-        a = if should_mutate(CHANGE_COLOR_RATE) { random::<u8>() % 130 + 125 } else { a };
+        r = self.rand_color(rng, config, r);
+        g = self.rand_color(rng, config, g);
+        b = self.rand_color(rng, config, b);
+        a = if should_mutate(rng, config.change_color_rate) {
+            if compressor.alpha { rng.gen::<u8>() } else { rng.gen::<u8>() % 130 + 125 }
+        } else {
+            a
+        };
         self.color = (r, g, b, a);
+        let mut changed = self.color != old_color;
 
-        if self.vertices.iter_mut().all(|v| v.mutate(compressor.dimensions)) {
+        // edges and bounds have to follow any vertex that moves, or renders
+        // (and anything rebuilt from the vertices) would disagree with them
+        let mut moved = false;
+        for v in self.vertices.iter_mut() {
+            moved = v.mutate(rng, config, compressor.dimensions) || moved;
+        }
+        if moved {
+            changed = true;
             self.vertices = order_points(self.vertices.clone());
             self.update_data();
         }
 
-        if should_mutate(ADD_VERTEX_RATE) {
-            let index = random::<uint>() % (self.vertices.len() - 1);
+        if should_mutate(rng, config.add_vertex_rate) {
+            changed = true;
+            let index = rng.gen::<uint>() % (self.vertices.len() - 1);
             let (u, v) = (self.vertices[index], self.vertices[index + 1]);
             self.vertices.insert(index + 1, (u + v) * 0.5);
             self.update_data();
         }
 
-        if should_mutate(REMOVE_VERTEX_RATE) && self.vertices.len() > 3 {
-            let index = random::<uint>() % self.vertices.len();
+        if should_mutate(rng, config.remove_vertex_rate) && self.vertices.len() > 3 {
+            changed = true;
+            let index = rng.gen::<uint>() % self.vertices.len();
             self.vertices.remove(index);
             self.update_data();
         }
 
-        if should_mutate(CHANGE_BLUR_RATE) {
-            self.blur = 0.5 + random::<f32>() * 0.5;
+        if should_mutate(rng, config.change_blur_rate) {
+            let old_blur = self.blur;
+            // sharp edges are the common case, so make it easy to get back to them
+            self.blur = if rng.gen::<bool>() { 0.0 } else { rng.gen::<f32>() * config.max_blur };
+            changed = changed || self.blur != old_blur;
         }
+
+        changed
     }
 }
 
@@ -400,13 +485,6 @@ impl fmt::Show for Encoding {
 
 impl Encoding {
     pub fn size(&self) -> uint {
-        let mut size = 16; // width + height + num_pixels + num_polygons
-        size += self.pixels.len() * (3 + 2); // color + position
-
-        for polygon in self.polygons.iter() {
-            size += 3 + 2 * polygon.vertices.len();
-        }
-
-        size
+        format::encoded_size(self)
     }
-}
\ No newline at end of file
+}
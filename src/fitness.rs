@@ -0,0 +1,465 @@
+use std::from_str::FromStr;
+use std::num::Float;
+use std::sync::Arc;
+use std::cmp::min;
+
+use encoding::{Color, fmax};
+use render::{premultiply, Image, Rect};
+use constants::{PIXEL_FIX_THRESHOLD, DELTA_E_FIX_THRESHOLD};
+use config::CompressorConfig;
+
+// Scores a render against the image being compressed. Lower is better and a
+// perfect match scores 0; scores are only compared with other scores from the
+// same metric, so their scale is up to the implementation.
+pub trait Fitness: Send + Sync {
+    fn error(&self, render: &Image) -> f64;
+
+    // The part of `error` that comes from the pixels in `rect`, given just those
+    // pixels. Metrics that aren't a sum over pixels return None, and candidates
+    // are then always scored on a full render.
+    #[allow(unused_variables)]
+    fn region_error(&self, rect: &Rect, render: &[Color]) -> Option<f64> {
+        None
+    }
+
+    // How wrong a single rendered pixel is, used to decide which pixels to fix.
+    // By default this weights the channels roughly by how sensitive the eye is to them.
+    fn pixel_error(&self, base: Color, render: Color) -> f32 {
+        let (br, bg, bb, ba) = premultiply(base);
+        let (nr, ng, nb, na) = premultiply(render);
+        0.9 * diff(br, nr) as f32
+            + 1.5 * diff(bg, ng) as f32
+            + 0.6 * diff(bb, nb) as f32
+            + diff(ba, na) as f32
+    }
+
+    // Pixels whose `pixel_error` exceeds this get fixed, unless the config says otherwise.
+    fn pixel_threshold(&self) -> f32 {
+        PIXEL_FIX_THRESHOLD
+    }
+}
+
+#[inline(always)]
+pub fn diff(a: u8, b: u8) -> uint {
+    let diff = if a > b { (a - b) as uint } else { (b - a) as uint };
+    diff
+}
+
+// Compares premultiplied colors, so the color of fully transparent pixels doesn't matter.
+#[inline(always)]
+pub fn color_diff(a: Color, b: Color) -> uint {
+    let (ar, ag, ab, aa) = premultiply(a);
+    let (br, bg, bb, ba) = premultiply(b);
+    diff(ar, br) + diff(ag, bg) + diff(ab, bb) + diff(aa, ba)
+}
+
+// Sum of absolute channel differences.
+pub struct AbsoluteError {
+    width: u32,
+    base: Arc<Image>,
+}
+
+impl AbsoluteError {
+    pub fn new(base: Arc<Image>, (w, _): (u32, u32)) -> AbsoluteError {
+        AbsoluteError { width: w, base: base }
+    }
+}
+
+impl Fitness for AbsoluteError {
+    fn error(&self, render: &Image) -> f64 {
+        let mut score = 0;
+        for (base, new) in self.base.iter().zip(render.iter()) {
+            score += color_diff(*base, *new);
+        }
+
+        score as f64
+    }
+
+    fn region_error(&self, rect: &Rect, render: &[Color]) -> Option<f64> {
+        let mut score = 0;
+        let mut pixels = render.iter();
+        for y in range(rect.y0, rect.y1) {
+            for x in range(rect.x0, rect.x1) {
+                score += color_diff(self.base[(y * self.width + x) as uint], *pixels.next().unwrap());
+            }
+        }
+
+        Some(score as f64)
+    }
+}
+
+// An image and successively halved, box-filtered copies of it, kept premultiplied.
+struct Pyramid {
+    levels: Vec<(uint, uint, Image)>,
+}
+
+impl Pyramid {
+    fn new(img: &Image, (w, h): (u32, u32), levels: uint) -> Pyramid {
+        let mut pyramid = Pyramid { levels: vec![] };
+        let (mut w, mut h) = (w as uint, h as uint);
+        let mut img: Image = img.iter().map(|&color| premultiply(color)).collect();
+        pyramid.levels.push((w, h, img.clone()));
+
+        for _ in range(0, levels) {
+            if w < 2 || h < 2 { break; }
+
+            let (nw, nh) = (w / 2, h / 2);
+            let mut next = Vec::with_capacity(nw * nh);
+            for y in range(0, nh) {
+                for x in range(0, nw) {
+                    let i = 2 * y * w + 2 * x;
+                    let block = [img[i], img[i + 1], img[i + w], img[i + w + 1]];
+                    let (mut r, mut g, mut b, mut a) = (0u, 0u, 0u, 0u);
+                    for &(br, bg, bb, ba) in block.iter() {
+                        r += br as uint;
+                        g += bg as uint;
+                        b += bb as uint;
+                        a += ba as uint;
+                    }
+                    next.push(((r / 4) as u8, (g / 4) as u8, (b / 4) as u8, (a / 4) as u8));
+                }
+            }
+
+            w = nw;
+            h = nh;
+            img = next;
+            pyramid.levels.push((w, h, img.clone()));
+        }
+
+        pyramid
+    }
+}
+
+// Absolute error summed over an image pyramid. Each coarser level is scaled up
+// to the pixel count of the full image and weighted by `weight` to the power of
+// its depth, so with a weight above 1 getting the coarse structure right pays
+// off more than fine detail.
+pub struct PyramidError {
+    dimensions: (u32, u32),
+    weight: f64,
+    base: Pyramid,
+}
+
+impl PyramidError {
+    pub fn new(base: &Image, dimensions: (u32, u32), levels: uint, weight: f64) -> PyramidError {
+        PyramidError { dimensions: dimensions,
+                       weight: weight,
+                       base: Pyramid::new(base, dimensions, levels) }
+    }
+}
+
+impl Fitness for PyramidError {
+    fn error(&self, render: &Image) -> f64 {
+        let pyramid = Pyramid::new(render, self.dimensions, self.base.levels.len() - 1);
+        let (mut error, mut level_weight, mut scale) = (0.0, 1.0, 1.0);
+        for (&(_, _, ref base), &(_, _, ref new)) in self.base.levels.iter().zip(pyramid.levels.iter()) {
+            let mut level_error = 0;
+            for (&(br, bg, bb, ba), &(nr, ng, nb, na)) in base.iter().zip(new.iter()) {
+                level_error += diff(br, nr) + diff(bg, ng) + diff(bb, nb) + diff(ba, na);
+            }
+
+            error += level_weight * scale * level_error as f64;
+            level_weight *= self.weight;
+            scale *= 4.0;
+        }
+
+        error
+    }
+}
+
+// A single channel of an image as floats, which is what SSIM works on.
+struct Plane {
+    w: uint,
+    h: uint,
+    data: Vec<f32>,
+}
+
+impl Plane {
+    // Luma of the premultiplied image, plus the alpha channel if the image has one.
+    fn from_image(img: &Image, (w, h): (u32, u32), alpha: bool) -> Vec<Plane> {
+        let (w, h) = (w as uint, h as uint);
+        let luma = img.iter().map(|&color| {
+            let (r, g, b, _) = premultiply(color);
+            0.299 * (r as f32) + 0.587 * (g as f32) + 0.114 * (b as f32)
+        }).collect();
+
+        let mut planes = vec![Plane { w: w, h: h, data: luma }];
+        if alpha {
+            let alpha = img.iter().map(|&(_, _, _, a)| a as f32).collect();
+            planes.push(Plane { w: w, h: h, data: alpha });
+        }
+
+        planes
+    }
+
+    // Halves both dimensions by averaging 2x2 blocks.
+    fn downsample(&self) -> Plane {
+        let (w, h) = (self.w / 2, self.h / 2);
+        let mut data = Vec::with_capacity(w * h);
+        for y in range(0, h) {
+            for x in range(0, w) {
+                let i = 2 * y * self.w + 2 * x;
+                data.push((self.data[i] + self.data[i + 1] +
+                           self.data[i + self.w] + self.data[i + self.w + 1]) * 0.25);
+            }
+        }
+
+        Plane { w: w, h: h, data: data }
+    }
+}
+
+static SSIM_WINDOW: uint = 8;
+static SSIM_STEP: uint = 4;
+static C1: f32 = (0.01 * 255.0) * (0.01 * 255.0);
+static C2: f32 = (0.03 * 255.0) * (0.03 * 255.0);
+
+// Mean SSIM and mean contrast-structure term over 8x8 windows spaced 4 pixels apart.
+fn ssim(x: &Plane, y: &Plane) -> (f32, f32) {
+    let win = min(SSIM_WINDOW, min(x.w, x.h));
+    let n = (win * win) as f32;
+    let (mut ssim_sum, mut cs_sum, mut count) = (0.0, 0.0, 0.0);
+
+    let mut wy = 0;
+    while wy + win <= x.h {
+        let mut wx = 0;
+        while wx + win <= x.w {
+            let (mut sx, mut sy, mut sxx, mut syy, mut sxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for j in range(wy, wy + win) {
+                for i in range(wx, wx + win) {
+                    let (a, b) = (x.data[j * x.w + i], y.data[j * y.w + i]);
+                    sx += a;
+                    sy += b;
+                    sxx += a * a;
+                    syy += b * b;
+                    sxy += a * b;
+                }
+            }
+
+            let (mx, my) = (sx / n, sy / n);
+            let (vx, vy, cov) = (sxx / n - mx * mx, syy / n - my * my, sxy / n - mx * my);
+            let cs = (2.0 * cov + C2) / (vx + vy + C2);
+            let luminance = (2.0 * mx * my + C1) / (mx * mx + my * my + C1);
+
+            ssim_sum += luminance * cs;
+            cs_sum += cs;
+            count += 1.0;
+            wx += SSIM_STEP;
+        }
+        wy += SSIM_STEP;
+    }
+
+    (ssim_sum / count, cs_sum / count)
+}
+
+// Structural similarity on luma (and alpha). The error is 1 - SSIM, averaged over planes.
+pub struct SsimError {
+    dimensions: (u32, u32),
+    alpha: bool,
+    base: Vec<Plane>,
+}
+
+impl SsimError {
+    pub fn new(base: &Image, dimensions: (u32, u32), alpha: bool) -> SsimError {
+        SsimError { dimensions: dimensions,
+                    alpha: alpha,
+                    base: Plane::from_image(base, dimensions, alpha) }
+    }
+}
+
+impl Fitness for SsimError {
+    fn error(&self, render: &Image) -> f64 {
+        let planes = Plane::from_image(render, self.dimensions, self.alpha);
+        let mut error = 0.0;
+        for (base, new) in self.base.iter().zip(planes.iter()) {
+            let (mean, _) = ssim(base, new);
+            error += 1.0 - mean as f64;
+        }
+
+        error / planes.len() as f64
+    }
+}
+
+// Weights of each scale from Wang et al., "Multi-scale structural similarity
+// for image quality assessment", finest scale first.
+static MS_SSIM_WEIGHTS: [f64, ..5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+
+// Multi-scale SSIM: contrast and structure are compared at up to five
+// successively halved resolutions, luminance only at the coarsest one.
+pub struct MsSsimError {
+    dimensions: (u32, u32),
+    alpha: bool,
+    base: Vec<Vec<Plane>>,
+}
+
+impl MsSsimError {
+    pub fn new(base: &Image, dimensions: (u32, u32), alpha: bool) -> MsSsimError {
+        MsSsimError { dimensions: dimensions,
+                      alpha: alpha,
+                      base: pyramid(Plane::from_image(base, dimensions, alpha)) }
+    }
+}
+
+// Every plane at each scale, stopping before a scale gets smaller than one SSIM window.
+fn pyramid(planes: Vec<Plane>) -> Vec<Vec<Plane>> {
+    planes.into_iter().map(|plane| {
+        let mut scales = vec![plane];
+        while scales.len() < MS_SSIM_WEIGHTS.len() {
+            let next = scales[scales.len() - 1].downsample();
+            if next.w < SSIM_WINDOW || next.h < SSIM_WINDOW { break; }
+            scales.push(next);
+        }
+        scales
+    }).collect()
+}
+
+impl Fitness for MsSsimError {
+    fn error(&self, render: &Image) -> f64 {
+        let planes = pyramid(Plane::from_image(render, self.dimensions, self.alpha));
+        let mut error = 0.0;
+        for (base, new) in self.base.iter().zip(planes.iter()) {
+            // renormalize the weights when the image is too small for all five scales
+            let total = MS_SSIM_WEIGHTS.iter().take(base.len()).fold(0.0, |a, b| a + *b);
+            let mut similarity = 1.0f64;
+            for (i, (x, y)) in base.iter().zip(new.iter()).enumerate() {
+                let (mean, cs) = ssim(x, y);
+                let term = if i == base.len() - 1 { mean } else { cs };
+                // negative correlation would make the product meaningless
+                similarity *= (fmax(term, 0.0) as f64).powf(MS_SSIM_WEIGHTS[i] / total);
+            }
+
+            error += 1.0 - similarity;
+        }
+
+        error / planes.len() as f64
+    }
+}
+
+// D65 reference white.
+static WHITE: (f32, f32, f32) = (0.95047, 1.0, 1.08883);
+
+type LabColor = (f32, f32, f32, f32);
+
+#[inline(always)]
+fn lab_f(t: f32) -> f32 {
+    if t > 216.0 / 24389.0 { t.powf(1.0 / 3.0) } else { (24389.0 / 27.0 * t + 16.0) / 116.0 }
+}
+
+// CIE 1976 color difference in CIELAB. Colors are premultiplied first and
+// alpha counts as a fourth axis with the same range as L*.
+pub struct DeltaE {
+    width: u32,
+    linear: Vec<f32>,
+    base: Vec<LabColor>,
+}
+
+impl DeltaE {
+    pub fn new(base: &Image, (w, _): (u32, u32)) -> DeltaE {
+        // sRGB transfer function, tabulated since channels only take 256 values
+        let linear = range(0u, 256).map(|c| {
+            let c = c as f32 / 255.0;
+            if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+        }).collect();
+
+        let mut delta_e = DeltaE { width: w, linear: linear, base: vec![] };
+        let lab = base.iter().map(|&color| delta_e.lab(color)).collect();
+        delta_e.base = lab;
+        delta_e
+    }
+
+    fn lab(&self, color: Color) -> LabColor {
+        let (r, g, b, a) = premultiply(color);
+        let (r, g, b) = (self.linear[r as uint], self.linear[g as uint], self.linear[b as uint]);
+        let (xn, yn, zn) = WHITE;
+
+        let fx = lab_f((0.4124 * r + 0.3576 * g + 0.1805 * b) / xn);
+        let fy = lab_f((0.2126 * r + 0.7152 * g + 0.0722 * b) / yn);
+        let fz = lab_f((0.0193 * r + 0.1192 * g + 0.9505 * b) / zn);
+
+        (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz), a as f32 * 100.0 / 255.0)
+    }
+
+    #[inline(always)]
+    fn distance(&self, (l1, a1, b1, t1): LabColor, (l2, a2, b2, t2): LabColor) -> f32 {
+        let (dl, da, db, dt) = (l1 - l2, a1 - a2, b1 - b2, t1 - t2);
+        (dl * dl + da * da + db * db + dt * dt).sqrt()
+    }
+}
+
+impl Fitness for DeltaE {
+    fn error(&self, render: &Image) -> f64 {
+        let mut error = 0.0;
+        for (base, &new) in self.base.iter().zip(render.iter()) {
+            error += self.distance(*base, self.lab(new)) as f64;
+        }
+
+        error
+    }
+
+    fn region_error(&self, rect: &Rect, render: &[Color]) -> Option<f64> {
+        let mut error = 0.0;
+        let mut pixels = render.iter();
+        for y in range(rect.y0, rect.y1) {
+            for x in range(rect.x0, rect.x1) {
+                let base = self.base[(y * self.width + x) as uint];
+                error += self.distance(base, self.lab(*pixels.next().unwrap())) as f64;
+            }
+        }
+
+        Some(error)
+    }
+
+    fn pixel_error(&self, base: Color, render: Color) -> f32 {
+        self.distance(self.lab(base), self.lab(render))
+    }
+
+    fn pixel_threshold(&self) -> f32 {
+        DELTA_E_FIX_THRESHOLD
+    }
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum FitnessMetric {
+    L1,
+    Ssim,
+    MsSsim,
+    Lab,
+    MultiScale,
+}
+
+impl FromStr for FitnessMetric {
+    fn from_str(s: &str) -> Option<FitnessMetric> {
+        match s {
+            "l1" => Some(L1),
+            "ssim" => Some(Ssim),
+            "ms-ssim" => Some(MsSsim),
+            "lab" => Some(Lab),
+            "pyramid" => Some(MultiScale),
+            _ => None,
+        }
+    }
+}
+
+impl FitnessMetric {
+    // The name `from_str` parses.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            L1 => "l1",
+            Ssim => "ssim",
+            MsSsim => "ms-ssim",
+            Lab => "lab",
+            MultiScale => "pyramid",
+        }
+    }
+
+    pub fn build(&self, base: Arc<Image>, dimensions: (u32, u32), alpha: bool, config: &CompressorConfig)
+                 -> Arc<Box<Fitness + Send + Sync>> {
+        Arc::new(match *self {
+            L1 => box AbsoluteError::new(base, dimensions) as Box<Fitness + Send + Sync>,
+            Ssim => box SsimError::new(&*base, dimensions, alpha) as Box<Fitness + Send + Sync>,
+            MsSsim => box MsSsimError::new(&*base, dimensions, alpha) as Box<Fitness + Send + Sync>,
+            Lab => box DeltaE::new(&*base, dimensions) as Box<Fitness + Send + Sync>,
+            MultiScale => box PyramidError::new(&*base, dimensions, config.pyramid_levels,
+                                                config.pyramid_weight as f64) as Box<Fitness + Send + Sync>,
+        })
+    }
+}
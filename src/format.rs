@@ -0,0 +1,311 @@
+use std::io::{File, IoResult, IoError, InvalidInput, BufferedReader, BufferedWriter};
+use std::num::Float;
+
+use encoding::{Encoding, Polygon, Pixel, Point, fmin, fmax};
+
+// Layout of a .gpe file (all integers big-endian):
+//
+//   magic "GPE", version u8, width u16, height u16, flags u8,
+//   antialiasing quality u8, polygon count u16, pixel count u32,
+//   per polygon: vertex count u16, color RGBA, blur u8 (in 1/16ths of a pixel),
+//   vertices as (x u16, y u16),
+//   per pixel: x u16, y u16, color RGB (RGBA if the alpha flag is set).
+//
+// Flags: bit 0 is set for encodings with an alpha channel.
+//
+// The antialiasing quality is the one the encoder scored renders with, so
+// decoders can draw the image the way it was optimised to look.
+//
+// Vertex coordinates are rounded to whole pixels on disk, so anything that
+// should match the decoded image must be computed on `quantize(img)`.
+
+static MAGIC: &'static [u8] = b"GPE";
+pub static VERSION: u8 = 1;
+
+pub static HEADER_SIZE: uint = 3 + 1 + 2 + 2 + 1 + 1 + 2 + 4;
+pub static POLYGON_SIZE: uint = 2 + 4 + 1;
+pub static VERTEX_SIZE: uint = 2 + 2;
+
+static FLAG_ALPHA: u8 = 1;
+static BLUR_SCALE: f32 = 16.0;
+
+pub fn pixel_size(alpha: bool) -> uint {
+    2 + 2 + if alpha { 4 } else { 3 }
+}
+
+fn invalid(desc: &'static str) -> IoError {
+    IoError { kind: InvalidInput, desc: desc, detail: None }
+}
+
+#[inline(always)]
+fn coordinate(v: f32, max: u32) -> u16 {
+    fmax(fmin(v.round(), (max - 1) as f32), 0.0) as u16
+}
+
+#[inline(always)]
+fn blur_byte(blur: f32) -> u8 {
+    fmax(fmin((blur * BLUR_SCALE).round(), 255.0), 0.0) as u8
+}
+
+pub fn encoded_size(img: &Encoding) -> uint {
+    let mut size = HEADER_SIZE + img.pixels.len() * pixel_size(img.alpha);
+    for polygon in img.polygons.iter() {
+        size += POLYGON_SIZE + polygon.vertices.len() * VERTEX_SIZE;
+    }
+
+    size
+}
+
+pub fn write<W: Writer>(img: &Encoding, quality: uint, w: &mut W) -> IoResult<()> {
+    let (width, height) = img.dimensions;
+    if width > 0xffff || height > 0xffff {
+        return Err(invalid("image dimensions do not fit in a GPE file"));
+    }
+    if quality > 0xff {
+        return Err(invalid("antialiasing quality does not fit in a GPE file"));
+    }
+    if img.polygons.len() > 0xffff {
+        return Err(invalid("too many polygons for a GPE file"));
+    }
+
+    try!(w.write(MAGIC));
+    try!(w.write_u8(VERSION));
+    try!(w.write_be_u16(width as u16));
+    try!(w.write_be_u16(height as u16));
+    try!(w.write_u8(if img.alpha { FLAG_ALPHA } else { 0 }));
+    try!(w.write_u8(quality as u8));
+    try!(w.write_be_u16(img.polygons.len() as u16));
+    try!(w.write_be_u32(img.pixels.len() as u32));
+
+    for polygon in img.polygons.iter() {
+        if polygon.vertices.len() > 0xffff {
+            return Err(invalid("too many vertices for a GPE file"));
+        }
+
+        let (r, g, b, a) = polygon.color;
+        try!(w.write_be_u16(polygon.vertices.len() as u16));
+        try!(w.write(&[r, g, b, a]));
+        try!(w.write_u8(blur_byte(polygon.blur)));
+        for vertex in polygon.vertices.iter() {
+            try!(w.write_be_u16(coordinate(vertex.x, width)));
+            try!(w.write_be_u16(coordinate(vertex.y, height)));
+        }
+    }
+
+    for pixel in img.pixels.iter() {
+        let (r, g, b, a) = pixel.color;
+        try!(w.write_be_u16(coordinate(pixel.pos.x, width)));
+        try!(w.write_be_u16(coordinate(pixel.pos.y, height)));
+        if img.alpha {
+            try!(w.write(&[r, g, b, a]));
+        } else {
+            try!(w.write(&[r, g, b]));
+        }
+    }
+
+    Ok(())
+}
+
+// Returns the encoding along with the antialiasing quality it was made with.
+pub fn read<R: Reader>(r: &mut R) -> IoResult<(Encoding, uint)> {
+    let magic = try!(r.read_exact(MAGIC.len()));
+    if magic.as_slice() != MAGIC {
+        return Err(invalid("not a GPE file"));
+    }
+    let version = try!(r.read_u8());
+    if version != VERSION {
+        return Err(invalid("unsupported GPE version"));
+    }
+
+    let width = try!(r.read_be_u16()) as u32;
+    let height = try!(r.read_be_u16()) as u32;
+    if width == 0 || height == 0 {
+        return Err(invalid("GPE file has empty dimensions"));
+    }
+
+    let alpha = try!(r.read_u8()) & FLAG_ALPHA != 0;
+    let quality = try!(r.read_u8()) as uint;
+    let num_polygons = try!(r.read_be_u16()) as uint;
+    let num_pixels = try!(r.read_be_u32()) as uint;
+    // each pixel can be fixed at most once
+    if num_pixels > (width * height) as uint {
+        return Err(invalid("GPE file has more fixed pixels than the image"));
+    }
+
+    // counts come from the file, so the vectors grow as entries are actually read
+    let mut polygons = Vec::new();
+    for _ in range(0, num_polygons) {
+        let num_vertices = try!(r.read_be_u16()) as uint;
+        if num_vertices < 3 {
+            return Err(invalid("GPE polygon has fewer than 3 vertices"));
+        }
+
+        let color = try!(r.read_exact(4));
+        let blur = try!(r.read_u8());
+
+        let mut vertices = Vec::new();
+        for _ in range(0, num_vertices) {
+            let x = try!(r.read_be_u16()) as u32;
+            let y = try!(r.read_be_u16()) as u32;
+            if x >= width || y >= height {
+                return Err(invalid("GPE vertex lies outside the image"));
+            }
+
+            vertices.push(Point { x: x as f32, y: y as f32 });
+        }
+
+        let mut polygon = Polygon::new(vertices, (color[0], color[1], color[2], color[3]));
+        polygon.blur = blur as f32 / BLUR_SCALE;
+        polygons.push(polygon);
+    }
+
+    let mut pixels = Vec::new();
+    for _ in range(0, num_pixels) {
+        let x = try!(r.read_be_u16()) as u32;
+        let y = try!(r.read_be_u16()) as u32;
+        if x >= width || y >= height {
+            return Err(invalid("GPE pixel lies outside the image"));
+        }
+
+        let color = if alpha {
+            let rgba = try!(r.read_exact(4));
+            (rgba[0], rgba[1], rgba[2], rgba[3])
+        } else {
+            let rgb = try!(r.read_exact(3));
+            (rgb[0], rgb[1], rgb[2], 255)
+        };
+
+        pixels.push(Pixel {
+            pos: Point { x: x as f32, y: y as f32 },
+            color: color
+        });
+    }
+
+    Ok((Encoding { dimensions: (width, height),
+                   alpha: alpha,
+                   polygons: polygons,
+                   pixels: pixels },
+        quality))
+}
+
+pub fn save(img: &Encoding, quality: uint, path: &Path) -> IoResult<()> {
+    let mut w = BufferedWriter::new(try!(File::create(path)));
+    try!(write(img, quality, &mut w));
+    w.flush()
+}
+
+pub fn open(path: &Path) -> IoResult<(Encoding, uint)> {
+    let mut r = BufferedReader::new(try!(File::open(path)));
+    read(&mut r)
+}
+
+// Rounds an encoding to exactly what `write` stores, so renders of the
+// result match renders of the decoded file.
+pub fn quantize(img: &Encoding) -> Encoding {
+    let (w, h) = img.dimensions;
+    let polygons = img.polygons.iter().map(|polygon| {
+        let vertices = polygon.vertices.iter().map(|v| {
+            Point { x: coordinate(v.x, w) as f32, y: coordinate(v.y, h) as f32 }
+        }).collect();
+
+        let mut quantized = Polygon::new(vertices, polygon.color);
+        quantized.blur = blur_byte(polygon.blur) as f32 / BLUR_SCALE;
+        quantized
+    }).collect();
+
+    Encoding { dimensions: img.dimensions,
+               alpha: img.alpha,
+               polygons: polygons,
+               pixels: img.pixels.clone() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{MemWriter, BufReader};
+
+    use encoding::{Encoding, Polygon, Pixel, Point};
+    use super::{read, write, quantize, encoded_size, HEADER_SIZE, POLYGON_SIZE, VERSION};
+
+    fn sample() -> Encoding {
+        let vertices = vec![Point { x: 1.2, y: 0.7 }, Point { x: 14.6, y: 3.0 },
+                            Point { x: 7.5, y: 9.4 }];
+        let mut polygon = Polygon::new(vertices, (200, 40, 10, 180));
+        polygon.blur = 0.75;
+
+        Encoding { dimensions: (16, 10),
+                   alpha: true,
+                   polygons: vec![polygon],
+                   pixels: vec![Pixel { pos: Point { x: 3.0, y: 4.0 }, color: (1, 2, 3, 4) }] }
+    }
+
+    fn written(img: &Encoding) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        write(img, 4, &mut w).unwrap();
+        w.get_ref().to_vec()
+    }
+
+    fn assert_same(a: &Encoding, b: &Encoding) {
+        assert_eq!(a.dimensions, b.dimensions);
+        assert_eq!(a.alpha, b.alpha);
+        assert_eq!(a.polygons.len(), b.polygons.len());
+        for (p, q) in a.polygons.iter().zip(b.polygons.iter()) {
+            assert_eq!(p.color, q.color);
+            assert_eq!(p.blur, q.blur);
+            assert_eq!(p.vertices.len(), q.vertices.len());
+            for (v, u) in p.vertices.iter().zip(q.vertices.iter()) {
+                assert_eq!((v.x, v.y), (u.x, u.y));
+            }
+        }
+        assert_eq!(a.pixels.len(), b.pixels.len());
+        for (p, q) in a.pixels.iter().zip(b.pixels.iter()) {
+            assert_eq!((p.pos.x, p.pos.y, p.color), (q.pos.x, q.pos.y, q.color));
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let img = sample();
+        let bytes = written(&img);
+        let (decoded, quality) = read(&mut BufReader::new(bytes.as_slice())).unwrap();
+
+        assert_eq!(quality, 4);
+        assert_same(&decoded, &quantize(&img));
+    }
+
+    #[test]
+    fn encoded_size_matches_write() {
+        let img = sample();
+        assert_eq!(encoded_size(&img), written(&img).len());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut bytes = written(&sample());
+        bytes[0] = b'X';
+        assert!(read(&mut BufReader::new(bytes.as_slice())).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let mut bytes = written(&sample());
+        bytes[3] = VERSION + 1;
+        assert!(read(&mut BufReader::new(bytes.as_slice())).is_err());
+    }
+
+    #[test]
+    fn rejects_vertex_outside_image() {
+        let mut bytes = written(&sample());
+        // x of the first vertex, set to the image width
+        bytes[HEADER_SIZE + POLYGON_SIZE] = 0;
+        bytes[HEADER_SIZE + POLYGON_SIZE + 1] = 16;
+        assert!(read(&mut BufReader::new(bytes.as_slice())).is_err());
+    }
+
+    #[test]
+    fn rejects_more_pixels_than_image() {
+        let mut bytes = written(&sample());
+        // the pixel count is the last field of the header
+        bytes[HEADER_SIZE - 2] = 0xff;
+        assert!(read(&mut BufReader::new(bytes.as_slice())).is_err());
+    }
+}
@@ -0,0 +1,26 @@
+#![feature(globs)]
+#![feature(phase)]
+#![feature(default_type_params)]
+#![allow(deprecated)]
+#[phase(plugin, link)] extern crate log;
+
+pub use compress::{compress, resume, Compressor, StopReason};
+pub use config::CompressorConfig;
+pub use encoding::{Encoding, Polygon, Pixel, Point, Color};
+pub use format::{open, save};
+pub use render::{render, Image};
+
+pub mod checkpoint;
+pub mod compress;
+pub mod config;
+pub mod crossover;
+pub mod encoding;
+pub mod fitness;
+pub mod format;
+pub mod render;
+pub mod rng;
+pub mod selection;
+pub mod svg;
+mod constants;
+mod fnvhasher;
+mod pool;
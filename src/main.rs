@@ -1,104 +1,331 @@
-#![feature(globs)]
-#![feature(phase)]
-#![feature(default_type_params)]
 #![allow(deprecated)]
-#[phase(plugin, link)] extern crate log;
 
 extern crate getopts;
 extern crate image;
+extern crate gpe;
 
 use std::os;
+use std::io;
 use std::io::File;
 use std::io::fs::PathExtensions;
+use std::default::Default;
+use std::cmp::max;
+use std::num::Float;
 
-use image::GenericImage;
-use encoding::Encoding;
-
-mod compress;
-mod render;
-mod encoding;
-mod constants;
-mod fnvhasher;
-
-static mut THRESHOLD: f32 = 0.0;
-static mut ITERATIONS: uint = 0;
+use gpe::{Encoding, CompressorConfig, checkpoint, format, render, svg};
 
 fn main() {
     let args = os::args();
-    let matches = match getopts::getopts(args.tail(), opts().as_slice()) {
+    let argv0 = args[0].as_slice();
+
+    if args.len() < 2 {
+        return usage(argv0, Some("expected a command"));
+    }
+
+    let (command, rest) = (args[1].as_slice(), args.slice_from(2));
+
+    match command {
+        "encode" => encode(argv0, rest),
+        "decode" => decode(argv0, rest),
+        "-h" | "--help" | "help" => usage(argv0, None),
+        _ => usage(argv0, Some(format!("unknown command `{}`", command).as_slice())),
+    }
+}
+
+fn encode(argv0: &str, args: &[String]) {
+    let opts = encode_opts();
+    let matches = match getopts::getopts(args, opts.as_slice()) {
         Ok(m) => m,
-        Err(err) => return println!("{}", err),
+        Err(err) => return fail(err.to_string()),
     };
 
     if matches.opt_present("h") {
-        return usage(args[0].as_slice(), None);
+        return command_usage(argv0, "encode", opts.as_slice(), None);
     }
 
     if matches.free.len() == 0 {
-        return usage(args[0].as_slice(), Some("expected an input image to compress"));
+        return command_usage(argv0, "encode", opts.as_slice(), Some("expected an input image to compress"));
     } if matches.free.len() > 1 {
-        return usage(args[0].as_slice(), Some("can only compress one file at a time"));
+        return command_usage(argv0, "encode", opts.as_slice(), Some("can only compress one file at a time"));
     }
 
-    let threshold = match matches.opt_str("t") {
-        Some(s) => from_str(s.as_slice()).unwrap(),
-        None => constants::FITNESS_THRESHOLD
+    // a resumed run starts from the settings it was checkpointed with
+    let resumed = match matches.opt_str("resume") {
+        Some(s) => match checkpoint::open(&Path::new(s.as_slice())) {
+            Ok(checkpoint) => Some(checkpoint),
+            Err(err) => return fail(format!("could not read {}: {}", s, err)),
+        },
+        None => None,
     };
-    unsafe { THRESHOLD = threshold; }
 
-    let iterations = match matches.opt_str("i") {
-        Some(s) => from_str(s.as_slice()).unwrap(),
-        None => 0
+    let mut config: CompressorConfig = match resumed {
+        Some(ref checkpoint) => checkpoint.config.clone(),
+        None => Default::default(),
     };
-    unsafe { ITERATIONS = iterations; }
+    match matches.opt_str("c") {
+        Some(s) => match config.load(&Path::new(s)) {
+            Ok(()) => {},
+            Err(err) => return fail(err),
+        },
+        None => {}
+    }
+    for assignment in matches.opt_strs("s").iter() {
+        match config.set_assignment(assignment.as_slice()) {
+            Ok(()) => {},
+            Err(err) => return fail(err),
+        }
+    }
+    match matches.opt_str("seed") {
+        Some(s) => match from_str(s.as_slice()) {
+            Some(seed) => config.seed = Some(seed),
+            None => return fail(format!("invalid seed `{}`", s)),
+        },
+        None => {}
+    }
+    match matches.opt_str("f") {
+        Some(s) => match from_str(s.as_slice()) {
+            Some(metric) => config.fitness = metric,
+            None => return fail(format!("unknown fitness metric `{}`", s)),
+        },
+        None => {}
+    }
+    match matches.opt_str("t") {
+        Some(s) => match from_str(s.as_slice()) {
+            Some(threshold) => config.threshold = threshold,
+            None => return fail(format!("invalid threshold `{}`", s)),
+        },
+        None => {}
+    }
+    match matches.opt_str("i") {
+        Some(s) => match from_str(s.as_slice()) {
+            Some(iterations) => config.iterations = iterations,
+            None => return fail(format!("invalid iteration count `{}`", s)),
+        },
+        None => {}
+    }
+    match matches.opt_str("plateau") {
+        Some(s) => match from_str(s.as_slice()) {
+            Some(window) => config.plateau_window = window,
+            None => return fail(format!("invalid plateau window `{}`", s)),
+        },
+        None => {}
+    }
+    match matches.opt_str("max-bytes") {
+        Some(s) => match from_str(s.as_slice()) {
+            Some(bytes) => config.max_bytes = bytes,
+            None => return fail(format!("invalid byte budget `{}`", s)),
+        },
+        None => {}
+    }
+    match matches.opt_str("time-limit") {
+        Some(s) => match from_str(s.as_slice()) {
+            Some(seconds) => config.time_limit = seconds,
+            None => return fail(format!("invalid time limit `{}`", s)),
+        },
+        None => {}
+    }
+    if matches.opt_present("debug-images") {
+        config.debug_images = true;
+    }
+    match matches.opt_str("checkpoint") {
+        Some(s) => config.checkpoint_path = Some(s),
+        None => {}
+    }
+    match config.validate() {
+        Ok(()) => {},
+        Err(err) => return fail(err),
+    }
 
     let input_path = Path::new(matches.free[0].clone());
-    let ((fixed, not_fixed), w, h) = match image::open(&input_path).unwrap() {
-        image::ImageRgb8(buf) => {
-            let (w, h) = buf.dimensions();
-            (compress::compress(buf), w, h)
-        },
-        _ => panic!("image must be RGB")
+    let output_path = match matches.opt_str("o") {
+        Some(s) => Path::new(s),
+        None => input_path.with_extension("gpe"),
     };
+    if output_path.extension_str() == Some("svg") {
+        return fail("encode only writes .gpe files; decode one with `-o out.svg` for an SVG".to_string());
+    }
 
-    let (in_size, out_size) = (input_path.stat().unwrap().size, fixed.size());
+    let img = match image::open(&input_path) {
+        Ok(img) => img,
+        Err(err) => return fail(format!("could not read {}: {}", input_path.display(), err)),
+    };
+
+    let quality = config.antialias;
+    let (fixed, not_fixed) = match resumed {
+        Some(checkpoint) => {
+            match gpe::resume(img, checkpoint, config) {
+                Ok(result) => result,
+                Err(err) => return fail(err),
+            }
+        }
+        None => gpe::compress(img, config),
+    };
+
+    match format::save(&fixed, quality, &output_path) {
+        Ok(()) => {},
+        Err(err) => return fail(format!("could not write {}: {}", output_path.display(), err)),
+    }
+
+    let (in_size, out_size) = (input_path.stat().unwrap().size, output_path.stat().unwrap().size);
     let percentage = (out_size as f32) / (in_size as f32) * 100.0;
     println!("{}% of original size ({} input, {} output, {} if not fixed)", percentage, in_size, out_size, not_fixed.size());
+}
+
+fn decode(argv0: &str, args: &[String]) {
+    let opts = decode_opts();
+    let matches = match getopts::getopts(args, opts.as_slice()) {
+        Ok(m) => m,
+        Err(err) => return fail(err.to_string()),
+    };
+
+    if matches.opt_present("h") {
+        return command_usage(argv0, "decode", opts.as_slice(), None);
+    }
+
+    if matches.free.len() == 0 {
+        return command_usage(argv0, "decode", opts.as_slice(), Some("expected an encoding to decode"));
+    } if matches.free.len() > 1 {
+        return command_usage(argv0, "decode", opts.as_slice(), Some("can only decode one file at a time"));
+    }
+
+    let input_path = Path::new(matches.free[0].clone());
+    let output_path = match matches.opt_str("o") {
+        Some(s) => Path::new(s),
+        None => input_path.with_extension("decoded.png"),
+    };
+
+    let (img, encoded_quality) = match format::open(&input_path) {
+        Ok(result) => result,
+        Err(err) => return fail(format!("could not read {}: {}", input_path.display(), err)),
+    };
+
+    let quality = if matches.opt_present("no-antialias") {
+        0
+    } else {
+        match matches.opt_str("a") {
+            Some(s) => match from_str(s.as_slice()) {
+                Some(quality) => quality,
+                None => return fail(format!("invalid antialiasing quality `{}`", s)),
+            },
+            None => encoded_quality,
+        }
+    };
+
+    let size = match (matches.opt_str("scale"), matches.opt_str("size")) {
+        (Some(_), Some(_)) => return fail("--scale and --size can't be used together".to_string()),
+        (Some(s), None) => match from_str::<f32>(s.as_slice()) {
+            Some(scale) if scale > 0.0 => {
+                let (w, h) = img.dimensions;
+                (max(1, (w as f32 * scale).round() as u32), max(1, (h as f32 * scale).round() as u32))
+            }
+            _ => return fail(format!("invalid scale `{}`", s)),
+        },
+        (None, Some(s)) => match parse_size(s.as_slice()) {
+            Some(size) => size,
+            None => return fail(format!("invalid size `{}`, expected WIDTHxHEIGHT", s)),
+        },
+        (None, None) => img.dimensions,
+    };
 
-    save_img(fixed, true, w, h);
-    save_img(not_fixed, false, w, h);
+    let saved = if output_path.extension_str() == Some("svg") {
+        svg::save(&img, size, &output_path).map_err(|err| err.to_string())
+    } else {
+        save_img(&img, quality, size, &output_path)
+    };
+
+    match saved {
+        Ok(()) => {},
+        Err(err) => fail(format!("could not write {}: {}", output_path.display(), err)),
+    }
 }
 
-fn save_img(img: Encoding, fixed: bool, w: u32, h: u32) {
-    let output = render::render(&img, true);
-    let save_file = File::create(&Path::new(if fixed { "out.fixed.png" } else { "out.nofixed.png" })).unwrap();
-    let pixels = output.into_iter().map(|(r, g, b)| image::Rgb(r, g, b)).collect();
-    let buf = image::ImageBuf::from_pixels(pixels, w, h);
-    let _ = image::ImageRgb8(buf).save(save_file, image::PNG);
+fn parse_size(s: &str) -> Option<(u32, u32)> {
+    let parts: Vec<&str> = s.split('x').collect();
+    if parts.len() != 2 { return None; }
+    match (from_str(parts[0]), from_str(parts[1])) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => Some((w, h)),
+        _ => None,
+    }
 }
 
-fn opts() -> Vec<getopts::OptGroup> {
-    use getopts::{optflag, optopt};
+fn save_img(img: &Encoding, quality: uint, (w, h): (u32, u32), path: &Path) -> Result<(), String> {
+    let output = if (w, h) == img.dimensions {
+        render::render(img, quality)
+    } else {
+        render::render_scaled(img, (w, h), quality)
+    };
+    let save_file = match File::create(path) {
+        Ok(f) => f,
+        Err(err) => return Err(err.to_string()),
+    };
+    let output = if img.alpha {
+        let pixels = output.into_iter().map(|(r, g, b, a)| image::Rgba(r, g, b, a)).collect();
+        image::ImageRgba8(image::ImageBuf::from_pixels(pixels, w, h))
+    } else {
+        let pixels = output.into_iter().map(|(r, g, b, _)| image::Rgb(r, g, b)).collect();
+        image::ImageRgb8(image::ImageBuf::from_pixels(pixels, w, h))
+    };
+    output.save(save_file, image::PNG).map_err(|err| err.to_string())
+}
+
+fn encode_opts() -> Vec<getopts::OptGroup> {
+    use getopts::{optflag, optopt, optmulti};
     vec![
         optflag("h", "help", "show this help message"),
+        optopt("o", "output", "write the encoding here (defaults to <input>.gpe)", "out.gpe"),
+        optopt("c", "config", "read compressor settings from a file of `key = value` lines", "FILE"),
+        optmulti("s", "set", "override a compressor setting, e.g. population_size=32", "KEY=VALUE"),
+        optopt("", "seed", "seed the random number generator to make the run reproducible", "42"),
+        optopt("f", "fitness", "metric the encoding is optimized for: l1, ssim, ms-ssim, lab or pyramid", "ssim"),
         optopt("t", "threshold", "terminate after reaching this fitness threshold", "0.75"),
         optopt("i", "iterations", "terminate after doing this many iterations", "1000"),
+        optopt("", "max-bytes", "keep the encoding, fixed pixels included, within this many bytes", "800"),
+        optopt("", "time-limit", "terminate after this many seconds, keeping the best encoding so far", "600"),
+        optopt("", "plateau", "terminate once the score stops improving for this many iterations", "500"),
+        optflag("", "debug-images", "write edges.png and error.png while compressing"),
+        optopt("", "checkpoint", "periodically save the run here so it can be resumed", "run.ckpt"),
+        optopt("", "resume", "carry on from a checkpoint made while compressing the same input", "run.ckpt"),
+        ]
+}
+
+fn decode_opts() -> Vec<getopts::OptGroup> {
+    use getopts::{optflag, optopt};
+    vec![
+        optflag("h", "help", "show this help message"),
+        optopt("o", "output", "write the PNG, or an SVG if it ends in .svg, here (defaults to <input>.decoded.png)", "out.png"),
+        optopt("a", "antialias", "samples per pixel row for polygon edges (defaults to the one stored by the encoder)", "N"),
+        optflag("", "no-antialias", "render polygons without antialiasing"),
+        optopt("", "scale", "render the image this many times larger (or smaller) than the original", "2"),
+        optopt("", "size", "render the image at exactly this size", "WIDTHxHEIGHT"),
         ]
 }
 
+// Reports an error on stderr and makes the process exit with a failure status,
+// so scripts running gpe can tell.
+fn fail(err: String) {
+    let _ = io::stderr().write_line(format!("error: {}", err).as_slice());
+    os::set_exit_status(1);
+}
+
 fn usage(argv0: &str, err: Option<&str>) {
     match err {
-        Some(e) => println!("error: {}", e),
+        Some(e) => fail(e.to_string()),
         None => {}
     }
-    println!("{}", getopts::usage(format!("{} [options] <input>", argv0).as_slice(),
-                                  opts().as_slice()));
+    println!("Usage: {} <command> [options] <input>", argv0);
+    println!("");
+    println!("Commands:");
+    println!("    encode    compress an image into a .gpe encoding");
+    println!("    decode    render a .gpe encoding to a PNG or SVG");
+    println!("");
+    println!("Run `{} <command> --help` for the options of each command.", argv0);
 }
 
-pub fn threshold() -> f32 {
-    unsafe { THRESHOLD }
+fn command_usage(argv0: &str, command: &str, opts: &[getopts::OptGroup], err: Option<&str>) {
+    match err {
+        Some(e) => fail(e.to_string()),
+        None => {}
+    }
+    println!("{}", getopts::usage(format!("{} {} [options] <input>", argv0, command).as_slice(),
+                                  opts));
 }
-
-pub fn iterations() -> uint {
-    unsafe { ITERATIONS }
-}
\ No newline at end of file
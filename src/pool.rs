@@ -0,0 +1,156 @@
+use std::sync::{Arc, Mutex};
+
+use encoding::Encoding;
+use fitness::Fitness;
+use render::{render, render_into, crop, patch, Image, Rect};
+
+// Which part of an offspring's render can differ from its parent's.
+pub enum Damage {
+    Clean,
+    Region(Rect),
+    Everything,
+}
+
+pub struct Offspring {
+    pub encoding: Encoding,
+    pub parent_render: Arc<Image>,
+    pub parent_error: f64,
+    pub damage: Damage,
+}
+
+// Buffers a worker keeps between jobs for the region it re-renders. The patched
+// full-size render each job returns is still a fresh allocation, since the
+// offspring keeps it.
+struct Scratch {
+    old: Image,
+    new: Image,
+}
+
+// Workers that score offspring for as long as the pool is alive. They are
+// spawned once and pull jobs off a shared queue, instead of a new set of
+// tasks being spun up every generation.
+pub struct WorkerPool {
+    jobs: Sender<(uint, Arc<Offspring>)>,
+    results: Receiver<(uint, f64, Arc<Image>)>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: uint, metric: Arc<Box<Fitness + Send + Sync>>, quality: uint) -> WorkerPool {
+        let (jobs, rjobs) = channel();
+        let (tresults, results) = channel();
+        let rjobs = Arc::new(Mutex::new(rjobs));
+
+        for _ in range(0, workers) {
+            let (rjobs, tresults, metric) = (rjobs.clone(), tresults.clone(), metric.clone());
+            spawn(proc() {
+                let mut scratch = Scratch { old: vec![], new: vec![] };
+                loop {
+                    let job = rjobs.lock().recv_opt();
+                    match job {
+                        Ok((i, offspring)) => {
+                            let (error, render) = evaluate(&**metric, &*offspring, quality,
+                                                           &mut scratch);
+                            tresults.send((i, error, render));
+                        }
+                        // the pool has been dropped
+                        Err(()) => break,
+                    }
+                }
+            });
+        }
+
+        WorkerPool { jobs: jobs, results: results }
+    }
+
+    // Scores every offspring, returning (error, render) pairs in the same order.
+    pub fn evaluate(&self, population: &[Arc<Offspring>]) -> Vec<(f64, Arc<Image>)> {
+        for (i, offspring) in population.iter().enumerate() {
+            self.jobs.send((i, offspring.clone()));
+        }
+
+        // results arrive in whatever order the workers finish
+        let mut scored = Vec::from_fn(population.len(), |_| None);
+        for _ in population.iter() {
+            let (i, error, render) = self.results.recv();
+            scored[i] = Some((error, render));
+        }
+
+        scored.into_iter().map(|result| result.unwrap()).collect()
+    }
+}
+
+fn evaluate(metric: &Box<Fitness + Send + Sync>, offspring: &Offspring, quality: uint,
+            scratch: &mut Scratch) -> (f64, Arc<Image>) {
+    let (w, _) = offspring.encoding.dimensions;
+    match offspring.damage {
+        Clean => return (offspring.parent_error, offspring.parent_render.clone()),
+        Region(ref rect) => {
+            crop(&*offspring.parent_render, w, rect, &mut scratch.old);
+            match metric.region_error(rect, scratch.old.as_slice()) {
+                Some(old_error) => {
+                    render_into(&offspring.encoding, quality, rect, &mut scratch.new);
+                    let new_error = metric.region_error(rect, scratch.new.as_slice()).unwrap();
+                    let mut new_render = (*offspring.parent_render).clone();
+                    patch(&mut new_render, w, rect, &scratch.new);
+                    return (offspring.parent_error - old_error + new_error, Arc::new(new_render));
+                }
+                // the metric can't score regions, so fall back to a full render
+                None => {}
+            }
+        }
+        Everything => {}
+    }
+
+    let new_render = render(&offspring.encoding, quality);
+    (metric.error(&new_render), Arc::new(new_render))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use encoding::{Encoding, Polygon, Point};
+    use fitness::{Fitness, AbsoluteError};
+    use render::{render, footprint, Image};
+    use super::{Offspring, Region, Scratch, evaluate};
+
+    fn polygon(points: &[(f32, f32)], color: (u8, u8, u8, u8), blur: f32) -> Polygon {
+        let vertices = points.iter().map(|&(x, y)| Point { x: x, y: y }).collect();
+        let mut polygon = Polygon::new(vertices, color);
+        polygon.blur = blur;
+        polygon
+    }
+
+    #[test]
+    fn region_score_matches_full_score() {
+        let dimensions = (32, 24);
+        let base: Image = Vec::from_fn(32 * 24, |i| ((i * 7) as u8, (i * 13) as u8, (i / 3) as u8, 255));
+        let metric = box AbsoluteError::new(Arc::new(base), dimensions) as Box<Fitness + Send + Sync>;
+
+        let polygons = vec![polygon(&[(1.5, 2.0), (25.0, 3.5), (9.0, 20.0)], (200, 30, 60, 200), 0.0),
+                            polygon(&[(10.0, 8.0), (22.0, 9.5), (16.0, 19.0)], (20, 180, 90, 160), 1.2),
+                            polygon(&[(4.0, 12.0), (14.5, 10.0), (12.0, 22.5)], (40, 40, 220, 120), 0.0)];
+        let parent = Encoding { dimensions: dimensions, alpha: false, polygons: polygons, pixels: vec![] };
+        let parent_render = render(&parent, 4);
+        let parent_error = metric.error(&parent_render);
+
+        // move the blurred polygon and recolor the last one
+        let mut child = parent.clone();
+        child.polygons[1] = polygon(&[(13.5, 5.0), (27.0, 7.5), (19.0, 16.5)], (20, 180, 90, 160), 1.2);
+        child.polygons[2] = polygon(&[(4.0, 12.0), (14.5, 10.0), (12.0, 22.5)], (90, 10, 10, 250), 0.0);
+        let dirty = footprint(&parent.polygons[1], dimensions)
+            .union(&footprint(&child.polygons[1], dimensions))
+            .union(&footprint(&child.polygons[2], dimensions));
+
+        let offspring = Offspring { encoding: child.clone(),
+                                    parent_render: Arc::new(parent_render),
+                                    parent_error: parent_error,
+                                    damage: Region(dirty) };
+        let mut scratch = Scratch { old: vec![], new: vec![] };
+        let (error, patched) = evaluate(&metric, &offspring, 4, &mut scratch);
+
+        let full = render(&child, 4);
+        assert_eq!(error, metric.error(&full));
+        assert!(*patched == full);
+    }
+}
@@ -1,85 +1,332 @@
-use std::iter::range_inclusive;
+use std::cmp::{min, max};
+use std::num::Float;
 
-use encoding::{Encoding, Point, Color, fmin};
+use encoding::{Encoding, Polygon, Point, Color, fmin, fmax, edge_distance_squared};
 
-type BufColor = (u8, u8, u8);
+type BufColor = Color;
 pub type Image = Vec<BufColor>;
 
-pub fn render(img: &Encoding, antialias: bool) -> Image {
+// How far outside its bounding box a polygon may touch pixels, before blur.
+static MARGIN: u32 = 4;
+// How many standard deviations out a blurred edge is still drawn.
+static BLUR_REACH: f32 = 3.0;
+
+// A pixel rectangle, with x1 and y1 exclusive.
+#[deriving(Clone, PartialEq, Show)]
+pub struct Rect {
+    pub x0: u32,
+    pub y0: u32,
+    pub x1: u32,
+    pub y1: u32,
+}
+
+impl Rect {
+    pub fn full((w, h): (u32, u32)) -> Rect {
+        Rect { x0: 0, y0: 0, x1: w, y1: h }
+    }
+
+    #[inline]
+    pub fn width(&self) -> u32 { if self.x1 > self.x0 { self.x1 - self.x0 } else { 0 } }
+
+    #[inline]
+    pub fn height(&self) -> u32 { if self.y1 > self.y0 { self.y1 - self.y0 } else { 0 } }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.width() == 0 || self.height() == 0
+    }
+
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() { return *other; }
+        if other.is_empty() { return *self; }
+        Rect { x0: min(self.x0, other.x0), y0: min(self.y0, other.y0),
+               x1: max(self.x1, other.x1), y1: max(self.y1, other.y1) }
+    }
+
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        Rect { x0: max(self.x0, other.x0), y0: max(self.y0, other.y0),
+               x1: min(self.x1, other.x1), y1: min(self.y1, other.y1) }
+    }
+}
+
+// Every pixel that rendering `polygon` can change.
+pub fn footprint(polygon: &Polygon, dimensions: (u32, u32)) -> Rect {
+    let (min, max) = polygon.bounding_box;
+    let margin = MARGIN + (polygon.blur * BLUR_REACH).ceil() as u32;
+    Rect { x0: if min.x > margin as f32 { min.x as u32 - margin } else { 0 },
+           y0: if min.y > margin as f32 { min.y as u32 - margin } else { 0 },
+           x1: max.x as u32 + margin + 1,
+           y1: max.y as u32 + margin + 1 }.intersect(&Rect::full(dimensions))
+}
+
+// Copies the pixels of `rect` out of a full render into `region`.
+pub fn crop(img: &Image, width: u32, rect: &Rect, region: &mut Image) {
+    region.clear();
+    for y in range(rect.y0, rect.y1) {
+        let row = (y * width) as uint;
+        region.push_all(img.slice(row + rect.x0 as uint, row + rect.x1 as uint));
+    }
+}
+
+// Writes a render of `rect` back into a full render.
+pub fn patch(img: &mut Image, width: u32, rect: &Rect, region: &Image) {
+    let rw = rect.width();
+    for y in range(rect.y0, rect.y1) {
+        for x in range(rect.x0, rect.x1) {
+            img[(y * width + x) as uint] = region[((y - rect.y0) * rw + (x - rect.x0)) as uint];
+        }
+    }
+}
+
+// `quality` is how many times each row of pixels is sampled to antialias
+// polygon edges; 0 draws them aliased.
+pub fn render(img: &Encoding, quality: uint) -> Image {
+    render_rect(img, quality, &Rect::full(img.dimensions))
+}
+
+// Renders at any resolution. Polygons are scaled as vectors, mapping pixel
+// centres to pixel centres, and each fixed pixel becomes the block of output
+// pixels it scales up to.
+pub fn render_scaled(img: &Encoding, (tw, th): (u32, u32), quality: uint) -> Image {
     let (w, h) = img.dimensions;
-    let mut imgbuf = Vec::from_fn((w * h) as uint, |_| (255, 255, 255));
+    let (sx, sy) = (tw as f32 / w as f32, th as f32 / h as f32);
+
+    let polygons = img.polygons.iter().map(|polygon| {
+        let vertices = polygon.vertices.iter().map(|v| {
+            Point { x: fmax((v.x + 0.5) * sx - 0.5, 0.0), y: fmax((v.y + 0.5) * sy - 0.5, 0.0) }
+        }).collect();
+
+        let mut scaled = Polygon::new(vertices, polygon.color);
+        scaled.blur = polygon.blur * (sx + sy) / 2.0;
+        scaled
+    }).collect();
+
+    let scaled = Encoding { dimensions: (tw, th),
+                            alpha: img.alpha,
+                            polygons: polygons,
+                            pixels: vec![] };
+    let mut imgbuf = render(&scaled, quality);
+
+    for pixel in img.pixels.iter() {
+        let (x0, x1) = ((pixel.pos.x * sx) as u32, ((pixel.pos.x + 1.0) * sx) as u32);
+        let (y0, y1) = ((pixel.pos.y * sy) as u32, ((pixel.pos.y + 1.0) * sy) as u32);
+        for y in range(y0, min(y1, th)) {
+            for x in range(x0, min(x1, tw)) {
+                imgbuf[(y * tw + x) as uint] = pixel.color;
+            }
+        }
+    }
+
+    imgbuf
+}
+
+// Renders only the pixels inside `rect`, in row-major order.
+pub fn render_rect(img: &Encoding, quality: uint, rect: &Rect) -> Image {
+    let mut imgbuf = vec![];
+    render_into(img, quality, rect, &mut imgbuf);
+    imgbuf
+}
+
+// Like `render_rect`, but draws into an existing buffer so callers rendering
+// over and over can reuse its allocation.
+pub fn render_into(img: &Encoding, quality: uint, rect: &Rect, imgbuf: &mut Image) {
+    let (rw, rh) = (rect.width(), rect.height());
+    let background = if img.alpha { (0, 0, 0, 0) } else { (255, 255, 255, 255) };
+    imgbuf.clear();
+    imgbuf.grow((rw * rh) as uint, background);
 
-    //let mut updated = Vec::with_capacity((w * h) as uint);
     for polygon in img.polygons.iter() {
-        let (min, max) = polygon.bounding_box;
-        //updated.clear();
+        let area = footprint(polygon, img.dimensions).intersect(rect);
+        if polygon.blur > 0.0 {
+            fill_blurred(polygon, &area, rect, imgbuf);
+        } else {
+            fill(polygon, quality, &area, rect, imgbuf);
+        }
+    }
 
-        for y in range_inclusive(min.y as u32 - 4, max.y as u32 + 4) {
-            for x in range_inclusive(min.x as u32 - 4, max.x as u32 + 4) {
-                if y >= h || x >= w { continue; }
+    for pixel in img.pixels.iter() {
+        let (x, y) = (pixel.pos.x as u32, pixel.pos.y as u32);
+        if x >= rect.x0 && x < rect.x1 && y >= rect.y0 && y < rect.y1 {
+            imgbuf[((y - rect.y0) * rw + (x - rect.x0)) as uint] = pixel.color;
+        }
+    }
+}
+
+#[inline(always)]
+fn top(&(a, b): &(Point, Point)) -> f32 { fmin(a.y, b.y) }
+
+#[inline(always)]
+fn bottom(&(a, b): &(Point, Point)) -> f32 { fmax(a.y, b.y) }
+
+// Draws one polygon over `area`, a part of `rect`, a scanline at a time.
+//
+// A point is inside when an odd number of its row's edge crossings lie to its
+// right, the same rule as `Polygon::query`, so each row is covered by the spans
+// between pairs of sorted crossings. With antialiasing every pixel row is
+// sampled `quality` times, and each pixel gets the exact fraction of every
+// sampled span that overlaps it; without, only pixel centres are tested.
+fn fill(polygon: &Polygon, quality: uint, area: &Rect, rect: &Rect, imgbuf: &mut Image) {
+    let edges = polygon.edges();
+    let rw = rect.width();
+    let samples = max(quality, 1);
+    let weight = 1.0 / samples as f32;
 
-                let pt = Point {x: x as f32, y: y as f32};
-                let (contains, dist) = polygon.query(&pt, antialias);
+    // edges in the order rows reach them
+    let mut pending: Vec<uint> = range(0, edges.len()).collect();
+    pending.sort_by(|&i, &j| top(&edges[i]).partial_cmp(&top(&edges[j])).unwrap_or(Equal));
+    let mut next = 0u;
+    let mut active: Vec<uint> = vec![];
 
-                if contains || (antialias && dist < 3.0 /*+ fmin(1.0 / polygon.blur, 5.0)*/) {
-                    let mut new_color = polygon.color;
+    let mut crossings: Vec<f32> = vec![];
+    let mut coverage = Vec::from_elem(area.width() as uint, 0.0f32);
 
-                    if !contains {
-                        let (r, g, b, a) = new_color;
-                        let scale = (1.0 + dist) * (1.0 + dist);
+    for y in range(area.y0, area.y1) {
+        // samples are spread evenly over the pixel, which spans y - 0.5 to y + 0.5
+        let fy = y as f32;
+        while next < pending.len() && top(&edges[pending[next]]) <= fy + 0.5 {
+            active.push(pending[next]);
+            next += 1;
+        }
+        active.retain(|&i| bottom(&edges[i]) >= fy - 0.5);
 
-                        new_color = (r, g, b, a / (scale as u8));
-                    }
+        for c in coverage.iter_mut() { *c = 0.0; }
+        for s in range(0, samples) {
+            let sy = if quality == 0 { fy } else { fy - 0.5 + (s as f32 + 0.5) * weight };
 
-                    let old_color = imgbuf[(y * w + x) as uint];
-                    imgbuf[(y * w + x) as uint] = blend(old_color, new_color);
-                    //updated.push((x, y));
+            crossings.clear();
+            for &i in active.iter() {
+                let (a, b) = edges[i];
+                if (a.y > sy) != (b.y > sy) {
+                    crossings.push((b.x - a.x) * (sy - a.y) / (b.y - a.y) + a.x);
                 }
             }
-        }
+            crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
 
-        /*for &(x, y) in updated.iter() {
-            let surrounding =
-                if polygon.blur > 0.5 {
-                    vec![(x - 1, y), (x, y - 1), (x, y + 1), (x + 1, y)]
+            for span in crossings.as_slice().chunks(2) {
+                if span.len() < 2 { break; }
+                if quality == 0 {
+                    cover_centres(coverage.as_mut_slice(), area, span[0], span[1]);
                 } else {
-                    let mut v = vec![];
-                    let n = fmin(1.0 / polygon.blur, 6.0) as u32;
-                    for i in range_inclusive(0, n) {
-                        for j in range_inclusive(0, n) {
-                            if i == 0 && j == 0 { continue; }
-                            v.push((x + i - n / 2, y + j - n / 2));
-                        }
-                    }
-                    v
-                };
-
-            let (br, bg, bb) = imgbuf[(y * w + x) as uint];
-            let (mut r, mut g, mut b) = (0.0, 0.0, 0.0);
-            let mut num_valid = 0.0;
-
-            for (px, py) in surrounding.into_iter() {
-                if px >= w || py >= h { continue; }
-                let (pr, pg, pb) = imgbuf[(py * w + px) as uint];
-                r += pr as f32;
-                g += pg as f32;
-                b += pb as f32;
-                num_valid += 1.0;
+                    cover_span(coverage.as_mut_slice(), area, span[0], span[1], weight);
+                }
+            }
+        }
+
+        let row = ((y - rect.y0) * rw) as uint;
+        let (r, g, b, a) = polygon.color;
+        for x in range(area.x0, area.x1) {
+            let alpha = (a as f32 * fmin(coverage[(x - area.x0) as uint], 1.0) + 0.5) as u8;
+            if alpha == 0 { continue; }
+
+            let i = row + (x - rect.x0) as uint;
+            let old_color = imgbuf[i];
+            imgbuf[i] = blend(old_color, (r, g, b, alpha));
+        }
+    }
+}
+
+// Draws a polygon whose edges are blurred by a gaussian, a scanline at a time.
+//
+// Along a straight edge, a sharp polygon convolved with a gaussian covers a
+// pixel by the gaussian's CDF at the pixel's signed distance from the edge, so
+// that is what every pixel within BLUR_REACH standard deviations of the
+// outline gets, using its nearest edge. Pixels farther in or out are fully
+// covered or untouched. The CDF is the usual logistic approximation.
+fn fill_blurred(polygon: &Polygon, area: &Rect, rect: &Rect, imgbuf: &mut Image) {
+    let edges = polygon.edges();
+    let rw = rect.width();
+    let sigma = polygon.blur;
+    let reach = sigma * BLUR_REACH;
+
+    let mut pending: Vec<uint> = range(0, edges.len()).collect();
+    pending.sort_by(|&i, &j| top(&edges[i]).partial_cmp(&top(&edges[j])).unwrap_or(Equal));
+    let mut next = 0u;
+    let mut active: Vec<uint> = vec![];
+
+    let mut crossings: Vec<f32> = vec![];
+    let mut inside = Vec::from_elem(area.width() as uint, 0.0f32);
+    let mut nearest = Vec::from_elem(area.width() as uint, 0.0f32);
+
+    for y in range(area.y0, area.y1) {
+        let fy = y as f32;
+        while next < pending.len() && top(&edges[pending[next]]) - reach <= fy {
+            active.push(pending[next]);
+            next += 1;
+        }
+        active.retain(|&i| bottom(&edges[i]) + reach >= fy);
+
+        crossings.clear();
+        for &i in active.iter() {
+            let (a, b) = edges[i];
+            if (a.y > fy) != (b.y > fy) {
+                crossings.push((b.x - a.x) * (fy - a.y) / (b.y - a.y) + a.x);
+            }
+        }
+        crossings.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Equal));
+
+        for v in inside.iter_mut() { *v = 0.0; }
+        for span in crossings.as_slice().chunks(2) {
+            if span.len() < 2 { break; }
+            cover_centres(inside.as_mut_slice(), area, span[0], span[1]);
+        }
+
+        for v in nearest.iter_mut() { *v = reach * reach; }
+        for &i in active.iter() {
+            let (a, b) = edges[i];
+            let x0 = fmax((fmin(a.x, b.x) - reach).floor(), area.x0 as f32) as u32;
+            let x1 = fmin((fmax(a.x, b.x) + reach).ceil() + 1.0, area.x1 as f32) as u32;
+            for x in range(x0, x1) {
+                let k = (x - area.x0) as uint;
+                let dist = edge_distance_squared(&Point {x: x as f32, y: fy}, a, b);
+                nearest[k] = if dist < nearest[k] { dist } else { nearest[k] };
             }
+        }
+
+        let row = ((y - rect.y0) * rw) as uint;
+        let (r, g, b, a) = polygon.color;
+        for x in range(area.x0, area.x1) {
+            let k = (x - area.x0) as uint;
+            let coverage = if nearest[k] >= reach * reach {
+                inside[k]
+            } else {
+                let dist = nearest[k].sqrt();
+                let signed = if inside[k] > 0.0 { dist } else { -dist };
+                1.0 / (1.0 + (-1.702 * signed / sigma).exp())
+            };
 
-            let add_scale = (1.0 - polygon.blur) / num_valid;
-            imgbuf[(y * w + x) as uint] =
-                ((r * add_scale + (br as f32) * polygon.blur) as u8,
-                 (g * add_scale + (bg as f32) * polygon.blur) as u8,
-                 (b * add_scale + (bb as f32) * polygon.blur) as u8);
-        }*/
+            let alpha = (a as f32 * coverage + 0.5) as u8;
+            if alpha == 0 { continue; }
+
+            let i = row + (x - rect.x0) as uint;
+            let old_color = imgbuf[i];
+            imgbuf[i] = blend(old_color, (r, g, b, alpha));
+        }
     }
+}
 
-    for pixel in img.pixels.iter() {
-        imgbuf[(pixel.pos.y * (w as f32) + pixel.pos.x) as uint] = pixel.color;
+// Fully covers the pixels whose centres lie in the span, i.e. start <= x < end.
+fn cover_centres(coverage: &mut [f32], area: &Rect, start: f32, end: f32) {
+    let x0 = fmax(start.ceil(), area.x0 as f32) as u32;
+    let x1 = fmin(end.ceil(), area.x1 as f32) as u32;
+    for x in range(x0, x1) {
+        coverage[(x - area.x0) as uint] = 1.0;
     }
+}
 
-    imgbuf
+// Adds how much of each pixel, which spans x - 0.5 to x + 0.5, the span covers.
+fn cover_span(coverage: &mut [f32], area: &Rect, start: f32, end: f32, weight: f32) {
+    let start = fmax(start, area.x0 as f32 - 0.5);
+    let end = fmin(end, area.x1 as f32 - 0.5);
+    if end <= start { return; }
+
+    let x0 = (start + 0.5).floor() as u32;
+    let x1 = min((end + 0.5).ceil() as u32, area.x1);
+    for x in range(x0, x1) {
+        let overlap = fmin(end, x as f32 + 0.5) - fmax(start, x as f32 - 0.5);
+        if overlap > 0.0 {
+            coverage[(x - area.x0) as uint] += overlap * weight;
+        }
+    }
 }
 
 #[inline(always)]
@@ -89,9 +336,111 @@ fn add(old: u8, new: u8, alpha: u8) -> u8 {
     //if addend + (old as u32) > 255 { 255 } else { (addend as u8) + old }
 }
 
+// Porter-Duff "over" for a destination that is only partially opaque.
+#[inline(always)]
+fn over(old: u8, old_alpha: u32, new: u8, alpha: u32, out_alpha: u32) -> u8 {
+    (((new as u32) * alpha + (old as u32) * old_alpha * (255 - alpha) / 255) / out_alpha) as u8
+}
+
 #[inline(always)]
 fn blend(old_color: BufColor, new_color: Color) -> BufColor {
-    let (or, og, ob) = old_color;
+    let (or, og, ob, oa) = old_color;
     let (nr, ng, nb, a) = new_color;
-    (add(or, nr, a), add(og, ng, a), add(ob, nb, a))
-}
\ No newline at end of file
+    if oa == 255 {
+        return (add(or, nr, a), add(og, ng, a), add(ob, nb, a), 255);
+    }
+
+    let (oa, a) = (oa as u32, a as u32);
+    let out_alpha = a + oa * (255 - a) / 255;
+    if out_alpha == 0 {
+        return (0, 0, 0, 0);
+    }
+
+    (over(or, oa, nr, a, out_alpha), over(og, oa, ng, a, out_alpha), over(ob, oa, nb, a, out_alpha),
+     out_alpha as u8)
+}
+
+#[inline(always)]
+pub fn premultiply((r, g, b, a): Color) -> Color {
+    if a == 255 { return (r, g, b, a); }
+    let scale = |c: u8| ((c as u32) * (a as u32) / 255) as u8;
+    (scale(r), scale(g), scale(b), a)
+}
+#[cfg(test)]
+mod tests {
+    use encoding::{Encoding, Polygon, Pixel, Point};
+    use super::{render, render_rect, crop, fill, Rect};
+
+    fn polygon(points: &[(f32, f32)], color: (u8, u8, u8, u8), blur: f32) -> Polygon {
+        let vertices = points.iter().map(|&(x, y)| Point { x: x, y: y }).collect();
+        let mut polygon = Polygon::new(vertices, color);
+        polygon.blur = blur;
+        polygon
+    }
+
+    fn sample(alpha: bool) -> Encoding {
+        Encoding { dimensions: (24, 18),
+                   alpha: alpha,
+                   polygons: vec![polygon(&[(2.3, 1.0), (20.7, 4.4), (8.1, 16.2)],
+                                          (200, 30, 60, 200), 0.0),
+                                  polygon(&[(6.0, 6.0), (18.0, 6.5), (17.2, 15.0), (5.5, 14.1)],
+                                          (20, 180, 90, 160), 1.5),
+                                  // concave, with a notch cut into its right side
+                                  polygon(&[(1.0, 9.0), (12.0, 9.0), (6.5, 12.5), (12.0, 17.0), (1.0, 17.0)],
+                                          (40, 40, 220, 120), 0.0),
+                                  polygon(&[(15.5, 0.0), (23.0, 2.0), (21.0, 10.0)],
+                                          (250, 250, 10, 90), 3.0)],
+                   pixels: vec![Pixel { pos: Point { x: 11.0, y: 7.0 }, color: (1, 2, 3, 255) }] }
+    }
+
+    #[test]
+    fn render_rect_matches_full_render() {
+        let rects = [Rect { x0: 0, y0: 0, x1: 24, y1: 18 },
+                     Rect { x0: 5, y0: 3, x1: 17, y1: 11 },
+                     Rect { x0: 0, y0: 10, x1: 24, y1: 18 },
+                     Rect { x0: 9, y0: 0, x1: 10, y1: 18 },
+                     Rect { x0: 23, y0: 17, x1: 24, y1: 18 }];
+
+        for &alpha in [false, true].iter() {
+            let img = sample(alpha);
+            for &quality in [0u, 1, 4].iter() {
+                let full = render(&img, quality);
+                for rect in rects.iter() {
+                    let mut expected = vec![];
+                    crop(&full, 24, rect, &mut expected);
+                    assert!(render_rect(&img, quality, rect) == expected,
+                            "alpha {}, quality {}, rect {}", alpha, quality, rect);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aliased_fill_matches_query() {
+        let black = (0, 0, 0, 255);
+        let polygons = [polygon(&[(2.3, 1.0), (20.7, 4.4), (8.1, 16.2)], black, 0.0),
+                        // concave
+                        polygon(&[(1.0, 9.0), (12.0, 9.0), (6.5, 12.5), (12.0, 17.0), (1.0, 17.0)], black, 0.0),
+                        // self-intersecting, so its middle is covered twice and left out
+                        polygon(&[(2.0, 2.0), (20.0, 14.0), (20.0, 2.0), (2.0, 14.0)], black, 0.0),
+                        // vertices and edges exactly on pixel centres
+                        polygon(&[(3.0, 3.0), (15.0, 3.0), (15.0, 11.0), (3.0, 11.0)], black, 0.0),
+                        // degenerate: all on one line, and a repeated vertex
+                        polygon(&[(1.0, 1.0), (10.0, 7.0), (19.0, 13.0)], black, 0.0),
+                        polygon(&[(4.0, 4.0), (4.0, 4.0), (18.5, 6.5), (9.0, 15.5)], black, 0.0)];
+
+        let rect = Rect::full((24, 18));
+        for polygon in polygons.iter() {
+            let mut imgbuf = Vec::from_elem(24 * 18, (255, 255, 255, 255));
+            fill(polygon, 0, &rect, &rect, &mut imgbuf);
+
+            for y in range(0u32, 18) {
+                for x in range(0u32, 24) {
+                    let (inside, _) = polygon.query(&Point { x: x as f32, y: y as f32 }, false);
+                    let covered = imgbuf[(y * 24 + x) as uint] == black;
+                    assert!(covered == inside, "polygon {}, pixel ({}, {})", polygon.vertices, x, y);
+                }
+            }
+        }
+    }
+}
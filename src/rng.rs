@@ -0,0 +1,66 @@
+use std::rand::{Rng, SeedableRng};
+
+// xorshift128. We carry our own generator instead of std's XorShiftRng so a
+// seed keeps producing the same compression across toolchains.
+#[deriving(Clone)]
+pub struct GpeRng {
+    x: u32,
+    y: u32,
+    z: u32,
+    w: u32,
+}
+
+impl GpeRng {
+    pub fn new(seed: u64) -> GpeRng {
+        SeedableRng::from_seed(seed)
+    }
+
+    // The whole generator state, to carry a run across a checkpoint.
+    pub fn state(&self) -> (u32, u32, u32, u32) {
+        (self.x, self.y, self.z, self.w)
+    }
+
+    pub fn from_state((x, y, z, w): (u32, u32, u32, u32)) -> GpeRng {
+        GpeRng { x: x, y: y, z: z, w: w }
+    }
+}
+
+impl Rng for GpeRng {
+    #[inline]
+    fn next_u32(&mut self) -> u32 {
+        let t = self.x ^ (self.x << 11);
+        self.x = self.y;
+        self.y = self.z;
+        self.z = self.w;
+        self.w = self.w ^ (self.w >> 19) ^ (t ^ (t >> 8));
+        self.w
+    }
+}
+
+#[inline]
+fn splitmix(state: &mut u64) -> u64 {
+    *state = *state + 0x9e3779b97f4a7c15;
+    let mut z = *state;
+    z = (z ^ (z >> 30)) * 0xbf58476d1ce4e5b9;
+    z = (z ^ (z >> 27)) * 0x94d049bb133111eb;
+    z ^ (z >> 31)
+}
+
+impl SeedableRng<u64> for GpeRng {
+    fn reseed(&mut self, seed: u64) {
+        *self = SeedableRng::from_seed(seed);
+    }
+
+    // Spread the seed over the whole state so that small seeds are as good as
+    // large ones. xorshift is stuck forever on an all-zero state, so avoid it.
+    fn from_seed(seed: u64) -> GpeRng {
+        let mut state = seed;
+        let (a, b) = (splitmix(&mut state), splitmix(&mut state));
+        let mut rng = GpeRng { x: (a >> 32) as u32, y: a as u32, z: (b >> 32) as u32, w: b as u32 };
+        if rng.x == 0 && rng.y == 0 && rng.z == 0 && rng.w == 0 {
+            rng.w = 1;
+        }
+
+        rng
+    }
+}
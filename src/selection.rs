@@ -0,0 +1,116 @@
+use std::from_str::FromStr;
+use std::rand::Rng;
+
+use rng::GpeRng;
+
+// Chooses the individuals that make up the next generation. `ranked` holds
+// (index, fitness) pairs sorted from best to worst, i.e. lowest fitness first.
+// Implementations return `count` indices; an index may be picked more than once.
+pub trait Selection {
+    fn select(&self, ranked: &[(uint, f64)], count: uint, rng: &mut GpeRng) -> Vec<uint>;
+}
+
+// Keeps the `count` best individuals.
+pub struct TruncationSelection;
+
+impl Selection for TruncationSelection {
+    fn select(&self, ranked: &[(uint, f64)], count: uint, _: &mut GpeRng) -> Vec<uint> {
+        ranked.iter().take(count).map(|&(index, _)| index).collect()
+    }
+}
+
+// Each survivor is the best of `size` individuals drawn at random. Larger
+// tournaments converge faster, smaller ones keep more diversity.
+pub struct TournamentSelection {
+    pub size: uint,
+}
+
+impl Selection for TournamentSelection {
+    fn select(&self, ranked: &[(uint, f64)], count: uint, rng: &mut GpeRng) -> Vec<uint> {
+        range(0, count).map(|_| {
+            // ranked is sorted, so the best contestant is the one with the lowest position
+            let winner = range(0, self.size).map(|_| rng.gen::<uint>() % ranked.len())
+                .min().unwrap_or(0);
+            ranked[winner].val0()
+        }).collect()
+    }
+}
+
+// Roulette wheel where the i-th best of n individuals gets a slice of size n - i.
+// Unlike fitness-proportional selection this doesn't care how far apart the scores are.
+pub struct RankSelection;
+
+impl Selection for RankSelection {
+    fn select(&self, ranked: &[(uint, f64)], count: uint, rng: &mut GpeRng) -> Vec<uint> {
+        let n = ranked.len();
+        let weights: Vec<f64> = range(0, n).map(|i| (n - i) as f64).collect();
+        spin(ranked, weights.as_slice(), count, rng)
+    }
+}
+
+// Fitness-proportional roulette wheel. Lower fitness is better, so each
+// individual's slice is how much better it is than the worst one.
+pub struct RouletteSelection;
+
+impl Selection for RouletteSelection {
+    fn select(&self, ranked: &[(uint, f64)], count: uint, rng: &mut GpeRng) -> Vec<uint> {
+        let (best, worst) = (ranked[0].val1(), ranked[ranked.len() - 1].val1());
+        // give the worst individual a small slice too, or it could never be picked
+        let floor = if worst > best { (worst - best) / ranked.len() as f64 } else { 1.0 };
+        let weights: Vec<f64> = ranked.iter().map(|&(_, fitness)| worst - fitness + floor).collect();
+        spin(ranked, weights.as_slice(), count, rng)
+    }
+}
+
+fn spin(ranked: &[(uint, f64)], weights: &[f64], count: uint, rng: &mut GpeRng) -> Vec<uint> {
+    let total = weights.iter().fold(0.0, |a, b| a + *b);
+    range(0, count).map(|_| {
+        let mut target = rng.gen::<f64>() * total;
+        for (i, weight) in weights.iter().enumerate() {
+            if target < *weight { return ranked[i].val0(); }
+            target -= *weight;
+        }
+        ranked[ranked.len() - 1].val0()
+    }).collect()
+}
+
+#[deriving(Clone, PartialEq, Show)]
+pub enum SelectionStrategy {
+    Truncation,
+    Tournament,
+    Rank,
+    Roulette,
+}
+
+impl FromStr for SelectionStrategy {
+    fn from_str(s: &str) -> Option<SelectionStrategy> {
+        match s {
+            "truncation" => Some(Truncation),
+            "tournament" => Some(Tournament),
+            "rank" => Some(Rank),
+            "roulette" => Some(Roulette),
+            _ => None,
+        }
+    }
+}
+
+impl SelectionStrategy {
+    // The name `from_str` parses.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Truncation => "truncation",
+            Tournament => "tournament",
+            Rank => "rank",
+            Roulette => "roulette",
+        }
+    }
+
+    pub fn build(&self, tournament_size: uint) -> Box<Selection + 'static> {
+        match *self {
+            Truncation => box TruncationSelection as Box<Selection>,
+            Tournament => box TournamentSelection { size: tournament_size } as Box<Selection>,
+            Rank => box RankSelection as Box<Selection>,
+            Roulette => box RouletteSelection as Box<Selection>,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::io::{File, IoResult, BufferedWriter};
+
+use encoding::{Encoding, Pixel};
+
+fn is_translucent(pixel: &Pixel) -> bool {
+    let (_, _, _, a) = pixel.color;
+    a < 255
+}
+
+// Writes an encoding as an SVG drawing displayed at `size`. The drawing keeps
+// the encoding's own coordinates in its viewBox, so viewers scale it to fit.
+//
+// Polygons become `<polygon>`s and fixed pixels 1x1 `<rect>`s, in the order
+// `render` draws them. Renders sample pixel (x, y) at its centre, while SVG
+// pixels span x to x + 1, so every coordinate is shifted by half a pixel.
+// Blurred polygons get a gaussian blur filter with the same standard deviation.
+// Fixed pixels replace whatever is under them in renders, so translucent ones
+// are drawn over a hole masked out of the polygons rather than blended onto them.
+pub fn write<W: Writer>(img: &Encoding, (display_w, display_h): (u32, u32), w: &mut W)
+                        -> IoResult<()> {
+    let (width, height) = img.dimensions;
+    try!(writeln!(w, "<svg xmlns=\"http://www.w3.org/2000/svg\" version=\"1.1\" \
+                      width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">",
+                  display_w, display_h, width, height));
+
+    let blurred = img.polygons.iter().any(|polygon| polygon.blur > 0.0);
+    let translucent = img.pixels.iter().any(|pixel| is_translucent(pixel));
+    if blurred || translucent {
+        try!(writeln!(w, "<defs>"));
+        for (i, polygon) in img.polygons.iter().enumerate() {
+            if polygon.blur > 0.0 {
+                try!(writeln!(w, "<filter id=\"blur{}\" filterUnits=\"userSpaceOnUse\" \
+                                  x=\"0\" y=\"0\" width=\"{}\" height=\"{}\">\
+                                  <feGaussianBlur stdDeviation=\"{}\"/></filter>",
+                              i, width, height, polygon.blur));
+            }
+        }
+        if translucent {
+            try!(writeln!(w, "<mask id=\"fixed\" maskUnits=\"userSpaceOnUse\" \
+                              x=\"0\" y=\"0\" width=\"{}\" height=\"{}\">", width, height));
+            try!(writeln!(w, "<rect width=\"{}\" height=\"{}\" fill=\"#ffffff\"/>", width, height));
+            for pixel in img.pixels.iter().filter(|pixel| is_translucent(*pixel)) {
+                try!(writeln!(w, "<rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\" fill=\"#000000\"/>",
+                              pixel.pos.x, pixel.pos.y));
+            }
+            try!(writeln!(w, "</mask>"));
+        }
+        try!(writeln!(w, "</defs>"));
+        if translucent {
+            try!(writeln!(w, "<g mask=\"url(#fixed)\">"));
+        }
+    }
+
+    // opaque encodings are rendered onto white
+    if !img.alpha {
+        try!(writeln!(w, "<rect width=\"{}\" height=\"{}\" fill=\"#ffffff\"/>", width, height));
+    }
+
+    for (i, polygon) in img.polygons.iter().enumerate() {
+        try!(write!(w, "<polygon points=\""));
+        for (j, vertex) in polygon.vertices.iter().enumerate() {
+            let sep = if j == 0 { "" } else { " " };
+            try!(write!(w, "{}{},{}", sep, vertex.x + 0.5, vertex.y + 0.5));
+        }
+
+        let (r, g, b, a) = polygon.color;
+        try!(write!(w, "\" fill=\"#{:02x}{:02x}{:02x}\" fill-rule=\"evenodd\"", r, g, b));
+        if a < 255 {
+            try!(write!(w, " fill-opacity=\"{}\"", a as f32 / 255.0));
+        }
+        if polygon.blur > 0.0 {
+            try!(write!(w, " filter=\"url(#blur{})\"", i));
+        }
+        try!(writeln!(w, "/>"));
+    }
+
+    if translucent {
+        try!(writeln!(w, "</g>"));
+    }
+
+    for pixel in img.pixels.iter() {
+        let (r, g, b, a) = pixel.color;
+        try!(write!(w, "<rect x=\"{}\" y=\"{}\" width=\"1\" height=\"1\" fill=\"#{:02x}{:02x}{:02x}\"",
+                    pixel.pos.x, pixel.pos.y, r, g, b));
+        if a < 255 {
+            try!(write!(w, " fill-opacity=\"{}\"", a as f32 / 255.0));
+        }
+        try!(writeln!(w, "/>"));
+    }
+
+    writeln!(w, "</svg>")
+}
+
+pub fn save(img: &Encoding, size: (u32, u32), path: &Path) -> IoResult<()> {
+    let mut w = BufferedWriter::new(try!(File::create(path)));
+    try!(write(img, size, &mut w));
+    w.flush()
+}